@@ -0,0 +1,191 @@
+use crate::{core::pool::BufferPool, utils::wayland::WaylandState};
+use log::debug;
+use parking_lot::Mutex;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use wayland_client::QueueHandle;
+
+/// Content that changes over time, drawn per output.
+pub trait Scene: Send + Sync {
+    /// Draws output `index` as it looks `elapsed` into the animation into
+    /// `dst`, a full frame in the output's shm format with 4 bytes per pixel
+    /// and no row padding.
+    fn draw(&self, index: usize, elapsed: Duration, dst: &mut [u8]);
+
+    /// Shortest time between frames.
+    fn frame_interval(&self) -> Duration;
+
+    /// Size of the frames drawn for output `index`, which the compositor
+    /// scales to the output.
+    fn size(&self, index: usize) -> (usize, usize);
+}
+
+/// The surfaces showing one output of an animation, and the pool its
+/// frames alternate through.
+pub struct AnimatedOutput {
+    pub index: usize,
+    pub surface_ids: Vec<u32>,
+    pub pool: Arc<Mutex<BufferPool>>,
+    last_frame: Option<Instant>,
+}
+
+impl AnimatedOutput {
+    pub fn new(index: usize, surface_ids: Vec<u32>, pool: Arc<Mutex<BufferPool>>) -> Self {
+        Self {
+            index,
+            surface_ids,
+            pool,
+            last_frame: None,
+        }
+    }
+
+    fn next_frame(&self, interval: Duration) -> Option<Instant> {
+        // Allow frames a little early so callback jitter doesn't halve the
+        // rate.
+        self.last_frame.map(|last| last + interval - interval / 4)
+    }
+
+    fn is_ready(&self, now: Instant, interval: Duration, state: &mut WaylandState) -> bool {
+        if self.next_frame(interval).is_some_and(|next| now < next) {
+            return false;
+        }
+        self.surface_ids.iter().all(|&id| {
+            state
+                .get_layer_surface(id)
+                .is_none_or(|surface| surface.is_draw_ready())
+        })
+    }
+}
+
+/// A running scene, redrawn from frame callbacks at most once per frame
+/// interval, and less often if drawing would use more CPU than allowed.
+pub struct Animation {
+    scene: Box<dyn Scene>,
+    outputs: Vec<AnimatedOutput>,
+    /// Set by the first step, so the animation picks up where its first
+    /// frame left off even if a transition delayed it.
+    start: Option<Instant>,
+    /// Share of one core the animation may keep busy.
+    cpu_budget: f64,
+    /// Moving average of the CPU time one output's frame takes.
+    cost: Option<Duration>,
+    /// When the animation was frozen, if it is.
+    paused_at: Option<Instant>,
+}
+
+impl Animation {
+    /// `outputs` should already show the scene's first frame.
+    pub fn new(scene: Box<dyn Scene>, outputs: Vec<AnimatedOutput>, cpu_budget: f64) -> Self {
+        Self {
+            scene,
+            outputs,
+            start: None,
+            cpu_budget,
+            cost: None,
+            paused_at: None,
+        }
+    }
+
+    /// Freezes the animation on its current frames.
+    pub fn pause(&mut self, now: Instant) {
+        self.paused_at.get_or_insert(now);
+    }
+
+    /// Continues from the frame shown when paused.
+    pub fn resume(&mut self, now: Instant) {
+        if let (Some(paused_at), Some(start)) = (self.paused_at.take(), &mut self.start) {
+            *start += now.saturating_duration_since(paused_at);
+        }
+        for output in &mut self.outputs {
+            output.last_frame = None;
+        }
+    }
+
+    /// The scene's frame interval, stretched so that drawing every output
+    /// stays within the CPU budget.
+    fn interval(&self) -> Duration {
+        let wanted = self.scene.frame_interval();
+        match self.cost {
+            Some(cost) if self.cpu_budget > 0.0 => {
+                let total = cost * self.outputs.len() as u32;
+                wanted.max(total.div_f64(self.cpu_budget))
+            }
+            _ => wanted,
+        }
+    }
+
+    /// When an output throttled by the frame interval may draw next, if
+    /// after `now`. Outputs waiting on a frame callback wake the daemon by
+    /// themselves.
+    pub fn next_wakeup(&self, now: Instant) -> Option<Instant> {
+        if self.paused_at.is_some() {
+            return None;
+        }
+        let interval = self.interval();
+        self.outputs
+            .iter()
+            .filter_map(|output| output.next_frame(interval))
+            .filter(|&next| next > now)
+            .min()
+    }
+
+    /// Draws the next frame on every output that is ready for one, as it
+    /// should look when it reaches the screen.
+    pub fn step(&mut self, now: Instant, state: &mut WaylandState, qh: &QueueHandle<WaylandState>) {
+        if self.paused_at.is_some() {
+            return;
+        }
+        let start = *self.start.get_or_insert(now);
+        let interval = self.interval();
+        for output in &mut self.outputs {
+            if !output.is_ready(now, interval, state) {
+                continue;
+            }
+            let target = output
+                .surface_ids
+                .iter()
+                .find_map(|&id| state.get_layer_surface(id)?.next_present(now))
+                .unwrap_or(now);
+            let elapsed = target.saturating_duration_since(start);
+
+            let scene = &self.scene;
+            let cpu_before = cpu_time();
+            let Some(buffer) = output.pool.lock().draw_with(state.get_shm(), qh, |dst| {
+                scene.draw(output.index, elapsed, dst)
+            }) else {
+                debug!("No free buffer for animation frame, skipping");
+                continue;
+            };
+            let cost = cpu_time().saturating_sub(cpu_before);
+            self.cost = Some(match self.cost {
+                Some(average) => average.mul_f64(0.9) + cost.mul_f64(0.1),
+                None => cost,
+            });
+            output.last_frame = Some(now);
+            for &id in &output.surface_ids {
+                if let Some(surface) = state.get_layer_surface(id) {
+                    surface.expect_present(target);
+                    surface.attach_buffer(&buffer, qh);
+                }
+            }
+        }
+    }
+
+    /// Stops the animation, handing back the pools its outputs show.
+    pub fn into_pools(self) -> Vec<Arc<Mutex<BufferPool>>> {
+        self.outputs.into_iter().map(|output| output.pool).collect()
+    }
+}
+
+/// CPU time used by the whole process so far, including rayon's workers.
+fn cpu_time() -> Duration {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return Duration::ZERO;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
@@ -1,43 +1,167 @@
 use crate::{
     core::{
+        animation::{AnimatedOutput, Animation, Scene},
         backend::LayerSurface,
-        buffer::Buffer,
-        cache::{Cache, CacheKey},
+        buffer::{Buffer, BufferState},
+        cache::{Cache, CacheKey, CacheStats},
+        disk_cache::DiskCache,
         pool::BufferPool,
+        presentation::FrameStats,
+        shm::ShmAllocator,
+        slideshow::{Slideshow, SlideshowState, SlideshowStatus},
+        swizzle::{self, PixelOrder},
+        transition::{ActiveTransition, OutputTransition, Target, Transition},
     },
     display::monitor::Monitor,
-    image::loader::ImageLoader,
+    image::{
+        color::Color,
+        dither::Dither,
+        generate::{Generator, GeneratorScene},
+        ken_burns::{KenBurns, KenBurnsScene},
+        loader::ImageLoader,
+        render::{Renderer, ScalingMode},
+        source::SourceId,
+        video::{Video, VideoScene},
+    },
     utils::{
+        config::Config,
         error::{WallpaperError, WallpaperResult},
+        power::PowerMonitor,
         wayland::WaylandState,
     },
 };
-use log::{debug, info};
-use parking_lot::RwLock;
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
+use parking_lot::{Mutex, RwLock};
 use rayon::prelude::*;
-use std::sync::Arc;
-use wayland_client::{Connection, EventQueue, Proxy};
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, RawFd},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use wayland_client::{
+    backend::WaylandError, protocol::wl_shm, Connection, EventQueue, Proxy, QueueHandle,
+};
+
+/// Images a slideshow tries before waiting for its next turn.
+const MAX_SLIDESHOW_ATTEMPTS: usize = 5;
+
+#[derive(Clone)]
+enum Wallpaper {
+    Image {
+        path: String,
+        mode: ScalingMode,
+        dither: Dither,
+        ken_burns: Option<KenBurns>,
+    },
+    Color(Color),
+    Generated {
+        generator: Generator,
+        dither: Dither,
+    },
+    Video(Video),
+}
+
+/// What a surface currently shows, kept to transition away from.
+#[derive(Clone)]
+enum Shown {
+    Pool(Arc<Mutex<BufferPool>>),
+    Color(Color),
+}
+
+impl Shown {
+    fn same(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Pool(a), Self::Pool(b)) => Arc::ptr_eq(a, b),
+            (Self::Color(a), Self::Color(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// A copy of the frame shown on `monitor`, laid out as `format`.
+    fn frame(&self, monitor: &Monitor, format: wl_shm::Format) -> Option<Vec<u8>> {
+        match self {
+            Self::Pool(pool) => pool.lock().frame().map(<[u8]>::to_vec),
+            Self::Color(color) => Some(solid_frame(*color, monitor, format)),
+        }
+    }
+}
+
+/// A full frame of `color` for `monitor`, laid out as `format`.
+fn solid_frame(color: Color, monitor: &Monitor, format: wl_shm::Format) -> Vec<u8> {
+    let mut pixel = [0; 4];
+    swizzle::upload(&color.rgba(), PixelOrder::Rgba, format, &mut pixel);
+    pixel.repeat(monitor.width as usize * monitor.height as usize)
+}
 
 pub struct App {
     monitors: Vec<Monitor>,
-    current_wallpaper: RwLock<Option<String>>,
+    current_wallpaper: RwLock<Option<Wallpaper>>,
     wayland_state: Option<WaylandState>,
     connection: Option<Connection>,
-    surfaces: Vec<LayerSurface>,
+    /// Layer surface ids in `WaylandState`, in the same order as `monitors`.
+    surface_ids: Vec<u32>,
     event_queue: Option<EventQueue<WaylandState>>,
-    cache: Arc<RwLock<Cache>>,
+    cache: Arc<Mutex<Cache>>,
+    allocator: Arc<Mutex<ShmAllocator>>,
+    hash_contents: bool,
+    disk_cache: Option<DiskCache>,
+    /// Buffers showing the current solid color, with the pool backing each
+    /// one when single-pixel buffers are unsupported.
+    color_buffers: Vec<(Buffer, Option<BufferPool>)>,
+    /// Color buffers replaced while a transition still blends away from
+    /// them, destroyed once it ends.
+    retired_color_buffers: Vec<(Buffer, Option<BufferPool>)>,
+    /// What each surface shows, in the same order as `monitors`.
+    shown: Vec<Option<Shown>>,
+    transition: Option<ActiveTransition>,
+    animation: Option<Animation>,
+    animation_cpu_budget: f64,
+    /// Zero when animations keep running while the user is idle.
+    idle_timeout: Duration,
+    /// Set when animations pause on battery.
+    power: Option<PowerMonitor>,
+    /// Whether animations and transitions are frozen.
+    paused: bool,
+    slideshow: Option<SlideshowState>,
 }
 
 impl App {
-    pub fn new() -> WallpaperResult<Self> {
+    pub fn new(config: &Config) -> WallpaperResult<Self> {
+        let disk_cache = match config.disk_cache_budget {
+            0 => None,
+            budget => match DiskCache::new(budget, config.disk_cache_max_age) {
+                Ok(cache) => Some(cache),
+                Err(e) => {
+                    warn!("Disk cache unavailable: {}", e);
+                    None
+                }
+            },
+        };
         let mut app = Self {
             monitors: Vec::new(),
             current_wallpaper: RwLock::new(None),
             wayland_state: None,
             connection: None,
-            surfaces: Vec::new(),
+            surface_ids: Vec::new(),
             event_queue: None,
-            cache: Arc::new(RwLock::new(Cache::new())),
+            cache: Arc::new(Mutex::new(Cache::new(config.cache_budget))),
+            allocator: Arc::new(Mutex::new(ShmAllocator::new(config.cache_budget))),
+            hash_contents: config.hash_contents,
+            disk_cache,
+            color_buffers: Vec::new(),
+            retired_color_buffers: Vec::new(),
+            shown: Vec::new(),
+            transition: None,
+            animation: None,
+            animation_cpu_budget: config.animation_cpu_budget,
+            idle_timeout: config.idle_timeout,
+            power: config
+                .pause_on_battery
+                .then(|| PowerMonitor::new(config.power_supply_path.clone())),
+            paused: false,
+            slideshow: None,
         };
         app.init_wayland()?;
         Ok(app)
@@ -55,6 +179,268 @@ impl App {
             .expect("Wayland state should be initialized")
     }
 
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            spare: self.allocator.lock().spare_bytes(),
+            ..self.cache.lock().stats()
+        }
+    }
+
+    pub fn disk_cache_stats(&self) -> Option<CacheStats> {
+        self.disk_cache.as_ref().map(DiskCache::stats)
+    }
+
+    /// Presentation counts for each output, by name.
+    pub fn frame_stats(&mut self) -> Vec<(String, FrameStats)> {
+        let state = self
+            .wayland_state
+            .as_mut()
+            .expect("Wayland state should be initialized");
+        self.monitors
+            .iter()
+            .zip(&self.surface_ids)
+            .filter_map(|(monitor, &id)| {
+                let stats = state.get_layer_surface(id)?.frame_stats();
+                Some((monitor.display_name(), stats))
+            })
+            .collect()
+    }
+
+    pub fn clear_cache(&self) {
+        let pools = self.cache.lock().clear();
+        self.recycle(pools);
+        // Keeps only the pools still on screen.
+        self.allocator.lock().trim();
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.clear();
+        }
+    }
+
+    /// Returns pools nothing else references to the allocator, which may
+    /// keep what the cache leaves of the budget.
+    fn recycle(&self, pools: Vec<Arc<Mutex<BufferPool>>>) {
+        let stats = self.cache.lock().stats();
+        let mut allocator = self.allocator.lock();
+        allocator.set_budget(stats.budget.saturating_sub(stats.bytes));
+        for pool in pools {
+            if let Ok(pool) = Arc::try_unwrap(pool) {
+                allocator.recycle(pool.into_inner());
+            }
+        }
+    }
+
+    /// The Wayland socket, to wait on for events.
+    pub fn connection_fd(&self) -> RawFd {
+        self.connection
+            .as_ref()
+            .expect("Connection should be initialized")
+            .as_fd()
+            .as_raw_fd()
+    }
+
+    /// Reads whatever events the socket holds without blocking, returning
+    /// `WouldBlock` if there were none.
+    pub fn read_events(&mut self) -> io::Result<()> {
+        let Some(guard) = self.event_queue().prepare_read() else {
+            // Events are already queued and just need dispatching.
+            return Ok(());
+        };
+        match guard.read() {
+            Ok(_) => Ok(()),
+            Err(WaylandError::Io(e)) => Err(e),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    /// Dispatches queued events, advances the running transition and
+    /// flushes the requests that produced.
+    pub fn dispatch(&mut self) -> WallpaperResult<()> {
+        let mut event_queue = self
+            .event_queue
+            .take()
+            .expect("Event queue should be initialized");
+        let mut state = self
+            .wayland_state
+            .take()
+            .expect("Wayland state should be initialized");
+        let qh = event_queue.handle();
+
+        let result = event_queue.dispatch_pending(&mut state);
+        if result.is_ok() {
+            self.animate(&mut state, &qh);
+        }
+        let flushed = event_queue.flush();
+
+        self.event_queue = Some(event_queue);
+        self.wayland_state = Some(state);
+        result?;
+        flushed?;
+        // Spare pools the compositor just released may be over budget.
+        self.allocator.lock().prune();
+        self.advance_slideshow();
+        Ok(())
+    }
+
+    /// When the daemon has to wake up even if no event arrives.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let now = Instant::now();
+        // Power only matters while something moves.
+        let power_check = self
+            .power
+            .as_ref()
+            .filter(|_| {
+                self.animation.is_some() || self.transition.is_some() || self.slideshow.is_some()
+            })
+            .map(PowerMonitor::next_check);
+        let slide = self
+            .slideshow
+            .as_ref()
+            .and_then(SlideshowState::next_change);
+        if self.paused {
+            return power_check.into_iter().chain(slide).min();
+        }
+        let next = match (&self.transition, &self.animation) {
+            (Some(transition), _) => Some(transition.next_wakeup(now)),
+            (None, Some(animation)) => animation.next_wakeup(now),
+            (None, None) => None,
+        };
+        next.into_iter().chain(power_check).chain(slide).min()
+    }
+
+    /// Pauses or resumes playback as the user goes idle or the power source
+    /// changes. Returns whether playback is paused.
+    fn update_paused(
+        &mut self,
+        now: Instant,
+        state: &mut WaylandState,
+        qh: &QueueHandle<WaylandState>,
+    ) -> bool {
+        let on_battery = self
+            .power
+            .as_mut()
+            .is_some_and(|power| power.on_battery(now));
+        let paused = state.is_idle() || on_battery;
+        if paused != self.paused {
+            self.paused = paused;
+            if paused {
+                info!("Pausing animations");
+                // Nobody watches the rest of the transition.
+                self.finish_transition(state, qh);
+                if let Some(animation) = &mut self.animation {
+                    animation.pause(now);
+                }
+                if let Some(slideshow) = &mut self.slideshow {
+                    slideshow.pause(now);
+                }
+            } else {
+                info!("Resuming animations");
+                if let Some(animation) = &mut self.animation {
+                    animation.resume(now);
+                }
+                if let Some(slideshow) = &mut self.slideshow {
+                    slideshow.resume(now);
+                }
+            }
+        } else if paused {
+            // Wallpapers set while paused cut straight to their last frame.
+            self.finish_transition(state, qh);
+            if let Some(animation) = &mut self.animation {
+                animation.pause(now);
+            }
+            if let Some(slideshow) = &mut self.slideshow {
+                slideshow.pause(now);
+            }
+        }
+        paused
+    }
+
+    /// Advances the running transition, or the animated wallpaper once no
+    /// transition is in the way.
+    fn animate(&mut self, state: &mut WaylandState, qh: &QueueHandle<WaylandState>) {
+        let now = Instant::now();
+        if self.update_paused(now, state, qh) {
+            return;
+        }
+        if let Some(transition) = &mut self.transition {
+            if transition.is_done(now) {
+                self.finish_transition(state, qh);
+            } else {
+                transition.step(now, state, qh);
+            }
+        } else if let Some(animation) = &mut self.animation {
+            animation.step(now, state, qh);
+        }
+    }
+
+    /// Jumps the running transition, if any, to its end.
+    fn finish_transition(&mut self, state: &mut WaylandState, qh: &QueueHandle<WaylandState>) {
+        let Some(transition) = self.transition.take() else {
+            return;
+        };
+        let mut allocator = self.allocator.lock();
+        for pool in transition.finish(state, qh) {
+            allocator.recycle(pool);
+        }
+        drop(allocator);
+        for (buffer, pool) in std::mem::take(&mut self.retired_color_buffers) {
+            self.destroy_color_buffer(buffer, pool);
+        }
+    }
+
+    /// Starts transitioning the surfaces of `indices` to `target`, grouping
+    /// surfaces that show the same frame. Returns the indices that have
+    /// nothing to transition from and should just be attached.
+    fn start_transitions(
+        &self,
+        indices: &[usize],
+        transition: &Transition,
+        target: impl Fn(&[usize]) -> Target,
+        format: wl_shm::Format,
+        outputs: &mut Vec<OutputTransition>,
+    ) -> WallpaperResult<Vec<usize>> {
+        let mut groups: Vec<(&Shown, Vec<usize>)> = Vec::new();
+        let mut direct = Vec::new();
+        for &i in indices {
+            match &self.shown[i] {
+                // Positional effects differ per output even for the same frame.
+                Some(shown) if transition.is_positional() => groups.push((shown, vec![i])),
+                Some(shown) => match groups.iter_mut().find(|(s, _)| s.same(shown)) {
+                    Some((_, members)) => members.push(i),
+                    None => groups.push((shown, vec![i])),
+                },
+                None => direct.push(i),
+            }
+        }
+
+        for (shown, members) in groups {
+            let monitor = &self.monitors[members[0]];
+            let frame_size = monitor.width as usize * monitor.height as usize * 4;
+            let Some(old) = shown
+                .frame(monitor, format)
+                .filter(|f| f.len() == frame_size)
+            else {
+                direct.extend(members);
+                continue;
+            };
+            let pool = self.allocator.lock().allocate(
+                monitor.width,
+                monitor.height,
+                monitor.width * 4,
+                format,
+            )?;
+            let surface_ids = members.iter().map(|&i| self.surface_ids[i]).collect();
+            outputs.push(OutputTransition::new(
+                surface_ids,
+                monitor,
+                transition,
+                old,
+                target(&members),
+                pool,
+            ));
+        }
+        Ok(direct)
+    }
+
     pub fn init_wayland(&mut self) -> WallpaperResult<()> {
         let conn = Connection::connect_to_env()?;
         let mut event_queue = conn.new_event_queue();
@@ -63,6 +449,9 @@ impl App {
         let mut state = WaylandState::new(&conn, &qh)?;
         event_queue.roundtrip(&mut state)?;
         event_queue.roundtrip(&mut state)?;
+        if !self.idle_timeout.is_zero() {
+            state.watch_idle(self.idle_timeout, &qh);
+        }
 
         self.monitors = state.get_monitors().to_vec();
         self.connection = Some(conn);
@@ -75,6 +464,54 @@ impl App {
 
     pub fn run_event_loop(&mut self) -> WallpaperResult<()> {
         info!("Starting event loop");
+        {
+            let event_queue = self
+                .event_queue
+                .as_mut()
+                .expect("Event queue should be initialized");
+            let state = self
+                .wayland_state
+                .as_mut()
+                .expect("Wayland state should be initialized");
+
+            debug!("Initial roundtrip");
+            event_queue.roundtrip(state)?;
+            event_queue.roundtrip(state)?;
+        }
+
+        let current = self.current_wallpaper.read().clone();
+        match current {
+            Some(Wallpaper::Image {
+                path,
+                mode,
+                dither,
+                ken_burns,
+            }) => {
+                debug!("Setting initial wallpaper");
+                self.set_wallpaper_and_exit(
+                    None,
+                    &path,
+                    &mode,
+                    dither,
+                    ken_burns,
+                    &Transition::default(),
+                )?;
+            }
+            Some(Wallpaper::Color(color)) => {
+                debug!("Setting initial color");
+                self.set_color(color, &Transition::default())?;
+            }
+            Some(Wallpaper::Generated { generator, dither }) => {
+                debug!("Starting initial generated background");
+                self.set_generated(generator, dither)?;
+            }
+            Some(Wallpaper::Video(video)) => {
+                debug!("Starting initial video");
+                self.set_video(video)?;
+            }
+            None => {}
+        }
+
         let mut event_queue = self
             .event_queue
             .take()
@@ -84,40 +521,6 @@ impl App {
             .take()
             .expect("Wayland state should be initialized");
 
-        debug!("Initial roundtrip");
-        event_queue.roundtrip(&mut state)?;
-        event_queue.roundtrip(&mut state)?;
-
-        let current_path = self.current_wallpaper.read().clone();
-
-        if let Some(path) = current_path {
-            debug!("Setting initial wallpaper");
-            let qh = &event_queue.handle();
-
-            let img = ImageLoader::preload(&path)?;
-            let buffers: Vec<_> = self
-                .monitors
-                .iter()
-                .enumerate()
-                .par_bridge()
-                .map(|(i, monitor)| {
-                    debug!("Creating new buffer for monitor {}", i);
-                    let scaled = ImageLoader::scale_image(
-                        &img,
-                        monitor.width as u32,
-                        monitor.height as u32,
-                    )?;
-                    let mut pool = BufferPool::new(monitor.width, monitor.height)?;
-                    pool.write_pixels(scaled.to_rgba8().as_raw());
-                    Ok::<Buffer, WallpaperError>(pool.get_buffer(state.get_shm(), qh).clone())
-                })
-                .collect::<Result<_, _>>()?;
-
-            for (surface, buffer) in self.surfaces.iter_mut().zip(buffers.iter()) {
-                surface.attach_buffer(buffer, qh);
-            }
-        }
-
         info!("Entering main event loop");
         loop {
             event_queue.blocking_dispatch(&mut state)?;
@@ -125,7 +528,7 @@ impl App {
     }
 
     fn recreate_surfaces(&mut self) -> WallpaperResult<()> {
-        self.surfaces.clear();
+        self.surface_ids.clear();
         let state = self
             .wayland_state
             .as_mut()
@@ -151,13 +554,17 @@ impl App {
                     state.get_layer_shell(),
                     state.get_compositor(),
                     Some(state.get_viewporter()),
+                    state.get_presentation(),
                 )
             })
             .collect::<Result<Vec<_>, _>>()?;
 
-        for surface in &new_surfaces {
+        let mut surface_ids = Vec::with_capacity(new_surfaces.len());
+        for surface in new_surfaces {
             debug!("Created layer surface with id: {:?}", surface.layer().id());
-            state.add_layer_surface(surface.layer().id().protocol_id(), surface.clone());
+            let id = surface.layer().id().protocol_id();
+            state.add_layer_surface(id, surface);
+            surface_ids.push(id);
         }
 
         const MAX_CONFIGURE_ATTEMPTS: u8 = 10;
@@ -169,13 +576,58 @@ impl App {
             }
         }
 
-        self.surfaces = new_surfaces;
+        self.shown = vec![None; surface_ids.len()];
+        self.surface_ids = surface_ids;
         self.event_queue = Some(event_queue);
         Ok(())
     }
 
-    pub fn set_wallpaper_and_exit(&mut self, path: &str) -> WallpaperResult<()> {
-        info!("Setting wallpaper: {}", path);
+    /// Sets the image on the output named `monitor`, or on every output.
+    pub fn set_wallpaper_and_exit(
+        &mut self,
+        monitor: Option<&str>,
+        path: &str,
+        mode: &ScalingMode,
+        dither: Dither,
+        ken_burns: Option<KenBurns>,
+        transition: &Transition,
+    ) -> WallpaperResult<()> {
+        let targets = self.targets(monitor)?;
+        self.set_wallpaper_on(&targets, path, mode, dither, ken_burns, transition, false)
+    }
+
+    /// Indices of the outputs named `monitor`, or of every output.
+    fn targets(&self, monitor: Option<&str>) -> WallpaperResult<Vec<usize>> {
+        let Some(monitor) = monitor else {
+            return Ok((0..self.monitors.len()).collect());
+        };
+        let targets: Vec<usize> = (0..self.monitors.len())
+            .filter(|&i| self.monitors[i].display_name() == monitor)
+            .collect();
+        if targets.is_empty() {
+            return Err(WallpaperError::InvalidMonitor(format!(
+                "no output named '{monitor}'"
+            )));
+        }
+        Ok(targets)
+    }
+
+    /// Like `set_wallpaper_and_exit`, but only on the outputs in `targets`.
+    /// Any animation stops on every output. With `join`, the running
+    /// transition, which must be on other outputs, takes in the new ones
+    /// rather than being cut short.
+    #[allow(clippy::too_many_arguments)]
+    fn set_wallpaper_on(
+        &mut self,
+        targets: &[usize],
+        path: &str,
+        mode: &ScalingMode,
+        dither: Dither,
+        ken_burns: Option<KenBurns>,
+        transition: &Transition,
+        join: bool,
+    ) -> WallpaperResult<()> {
+        info!("Setting wallpaper on outputs {:?}: {}", targets, path);
         debug!("Starting wallpaper setting process");
 
         let mut event_queue = self
@@ -186,55 +638,497 @@ impl App {
             .wayland_state
             .take()
             .expect("Wayland state should be initialized");
+        // Put back even on failure, as the daemon keeps running.
+        let result = self.show_wallpaper_on(
+            &mut event_queue,
+            &mut state,
+            targets,
+            path,
+            mode,
+            dither,
+            ken_burns,
+            transition,
+            join,
+        );
+        self.event_queue = Some(event_queue);
+        self.wayland_state = Some(state);
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn show_wallpaper_on(
+        &mut self,
+        event_queue: &mut EventQueue<WaylandState>,
+        state: &mut WaylandState,
+        targets: &[usize],
+        path: &str,
+        mode: &ScalingMode,
+        dither: Dither,
+        ken_burns: Option<KenBurns>,
+        transition: &Transition,
+        join: bool,
+    ) -> WallpaperResult<()> {
         let qh = event_queue.handle();
+        if !join {
+            self.finish_transition(state, &qh);
+        }
+        let transition = &transition.resolve();
         let cache = Arc::clone(&self.cache);
+        let allocator = Arc::clone(&self.allocator);
+
+        debug!("Creating buffers for {} monitors", targets.len());
+        let format = state.preferred_format();
+        let order = PixelOrder::native(format);
+        debug!("Uploading as {:?} with {:?} source pixels", format, order);
+        let source = SourceId::new(path, self.hash_contents)?;
+        // Only decoded if some output misses both caches.
+        let img = OnceCell::new();
+        let renderer = Renderer::new(mode, dither, &self.monitors);
+        let scene = match ken_burns {
+            Some(params) => Some(KenBurnsScene::new(
+                params,
+                &*ImageLoader::load(&source, order)?,
+                mode,
+                &self.monitors,
+            )?),
+            None => None,
+        };
 
-        debug!("Creating buffers for {} monitors", self.monitors.len());
-        let img = ImageLoader::preload(path)?;
+        let mut groups: Vec<(CacheKey, Vec<usize>)> = Vec::new();
+        for &i in targets {
+            let monitor = &self.monitors[i];
+            let cache_key = CacheKey::new(
+                &source,
+                monitor.width.try_into().unwrap(),
+                monitor.height.try_into().unwrap(),
+                format.into(),
+                renderer.cache_tag(i),
+            );
+            // Every output of a Ken Burns pan shows a different window.
+            match groups
+                .iter_mut()
+                .find(|(key, _)| scene.is_none() && *key == cache_key)
+            {
+                Some((_, indices)) => indices.push(i),
+                None => groups.push((cache_key, vec![i])),
+            }
+        }
+        debug!(
+            "Rendering {} unique buffers for {} monitors",
+            groups.len(),
+            targets.len()
+        );
 
-        let buffers: Vec<_> = self
-            .monitors
-            .iter()
-            .enumerate()
-            .par_bridge()
-            .map(|(i, monitor)| {
-                let cache_key = CacheKey::new(
-                    path,
-                    monitor.width.try_into().unwrap(),
-                    monitor.height.try_into().unwrap(),
-                );
+        let pools: Vec<_> = groups
+            .into_par_iter()
+            .map(|(cache_key, indices)| {
+                let i = indices[0];
+                let monitor = &self.monitors[i];
+                let allocate = || {
+                    allocator.lock().allocate(
+                        monitor.width,
+                        monitor.height,
+                        monitor.width * 4,
+                        format,
+                    )
+                };
 
-                if let Some(buffer) = cache.read().get(&cache_key) {
-                    return Ok(buffer.clone());
+                // Animated frames are neither cached nor reused.
+                if let Some(scene) = &scene {
+                    let mut pool = allocate()?;
+                    pool.fill_frame(|frame| {
+                        scene.draw(i, Duration::ZERO, frame);
+                        true
+                    });
+                    return Ok((indices, Arc::new(Mutex::new(pool)), Vec::new()));
                 }
 
-                debug!("Creating new buffer for monitor {}", i);
-                let scaled =
-                    ImageLoader::scale_image(&img, monitor.width as u32, monitor.height as u32)?;
-                let mut pool = BufferPool::new(monitor.width, monitor.height)?;
-                pool.write_pixels(scaled.to_rgba8().as_raw());
-                let buffer = pool.get_buffer(state.get_shm(), &qh).clone();
+                if let Some(pool) = cache.lock().get(&cache_key) {
+                    return Ok((indices, Arc::clone(pool), Vec::new()));
+                }
 
-                cache.write().insert(cache_key, buffer.clone());
-                Ok::<Buffer, WallpaperError>(buffer)
+                let mut pool = allocate()?;
+                let from_disk = self.disk_cache.as_ref().is_some_and(|disk_cache| {
+                    pool.fill_frame(|frame| disk_cache.load(&cache_key, frame))
+                });
+                if !from_disk {
+                    debug!("Creating new buffer for monitors {:?}", indices);
+                    let img = img.get_or_try_init(|| ImageLoader::load(&source, order))?;
+                    let frame = renderer.render_frame(img, i, monitor)?;
+                    pool.write_pixels(frame.as_raw(), order);
+                    if let (Some(disk_cache), Some(frame)) = (&self.disk_cache, pool.frame()) {
+                        disk_cache.store(cache_key.clone(), frame.to_vec());
+                    }
+                }
+                let pool = Arc::new(Mutex::new(pool));
+
+                let evicted = cache.lock().insert(cache_key, Arc::clone(&pool));
+                Ok::<_, WallpaperError>((indices, pool, evicted))
             })
             .collect::<Result<_, _>>()?;
+        let previous_animation = self.animation.take();
 
         debug!("Attaching buffers to surfaces");
-        for (i, (surface, buffer)) in self.surfaces.iter_mut().zip(buffers).enumerate() {
-            debug!(
-                "Attaching buffer {:?} to surface {}",
-                buffer.buffer().id(),
-                i
-            );
-            surface.attach_buffer(&buffer, &qh);
+        let mut evicted = Vec::new();
+        let mut outputs = Vec::new();
+        let mut animated = Vec::new();
+        for (indices, pool, mut group_evicted) in pools {
+            evicted.append(&mut group_evicted);
+            if scene.is_some() {
+                let surface_ids = indices.iter().map(|&i| self.surface_ids[i]).collect();
+                animated.push(AnimatedOutput::new(
+                    indices[0],
+                    surface_ids,
+                    Arc::clone(&pool),
+                ));
+            }
+            let direct = if transition.is_animated() {
+                self.start_transitions(
+                    &indices,
+                    transition,
+                    |_| Target::Pool(Arc::clone(&pool)),
+                    format,
+                    &mut outputs,
+                )?
+            } else {
+                indices.clone()
+            };
+            for &i in &indices {
+                self.shown[i] = Some(Shown::Pool(Arc::clone(&pool)));
+            }
+            if direct.is_empty() {
+                continue;
+            }
+
+            let Some(buffer) = pool.lock().acquire_buffer(state.get_shm(), &qh) else {
+                warn!("All buffers for surfaces {:?} are busy, skipping", direct);
+                continue;
+            };
+            for i in direct {
+                let Some(surface) = state.get_layer_surface(self.surface_ids[i]) else {
+                    continue;
+                };
+                debug!(
+                    "Attaching buffer {:?} to surface {}",
+                    buffer.buffer().id(),
+                    i
+                );
+                surface.attach_buffer(&buffer, &qh);
+            }
         }
 
-        self.current_wallpaper = RwLock::new(Some(path.to_string()));
-        event_queue.roundtrip(&mut state)?;
+        self.current_wallpaper = RwLock::new(Some(Wallpaper::Image {
+            path: path.to_string(),
+            mode: mode.clone(),
+            dither,
+            ken_burns,
+        }));
+        if !outputs.is_empty() {
+            match &mut self.transition {
+                Some(running) if join => running.extend(outputs),
+                _ => self.transition = Some(ActiveTransition::new(transition, outputs)),
+            }
+        }
+        if let Some(scene) = scene {
+            self.animation = Some(Animation::new(
+                Box::new(scene),
+                animated,
+                self.animation_cpu_budget,
+            ));
+        }
+        event_queue.roundtrip(state)?;
+        // Other outputs may still show the color.
+        if !self
+            .shown
+            .iter()
+            .flatten()
+            .any(|s| matches!(s, Shown::Color(_)))
+        {
+            self.destroy_color_buffers(Vec::new());
+        }
+        // After the roundtrip so releases of the old frames have arrived.
+        self.recycle(evicted);
+        if let Some(animation) = previous_animation {
+            self.recycle(animation.into_pools());
+        }
+        Ok(())
+    }
 
+    /// Starts `slideshow`, replacing any running one, and shows its first
+    /// images.
+    pub fn start_slideshow(&mut self, slideshow: Slideshow) -> WallpaperResult<()> {
+        let names = self.monitors.iter().map(Monitor::display_name).collect();
+        let state = SlideshowState::new(slideshow, names, Instant::now())?;
+        self.slideshow = Some(state);
+        self.advance_slideshow();
+        Ok(())
+    }
+
+    /// Stops the slideshow, leaving its current images up.
+    pub fn stop_slideshow(&mut self) {
+        if self.slideshow.take().is_some() {
+            info!("Stopped slideshow");
+        }
+    }
+
+    pub fn slideshow_status(&self) -> Option<SlideshowStatus> {
+        let slideshow = self.slideshow.as_ref()?;
+        Some(slideshow.status(Instant::now()))
+    }
+
+    /// Shows the next image on every slideshow output whose interval is up,
+    /// skipping images that fail to load.
+    fn advance_slideshow(&mut self) {
+        let now = Instant::now();
+        let Some(slideshow) = &self.slideshow else {
+            return;
+        };
+        let dither = slideshow.slideshow().dither;
+        // Outputs changing together share one transition.
+        let mut join = false;
+        for track in slideshow.due(now) {
+            // Bounded so a directory of broken images doesn't spin.
+            for _ in 0..MAX_SLIDESHOW_ATTEMPTS {
+                let Some(slideshow) = &mut self.slideshow else {
+                    return;
+                };
+                let Some((targets, slide)) = slideshow.advance(track) else {
+                    debug!("No slide for the outputs of slideshow track {}", track);
+                    break;
+                };
+                let result = self.set_wallpaper_on(
+                    &targets,
+                    &slide.path.to_string_lossy(),
+                    &slide.mode,
+                    dither,
+                    None,
+                    &slide.transition,
+                    join,
+                );
+                match result {
+                    Ok(()) => {
+                        join = true;
+                        break;
+                    }
+                    Err(e) => warn!("Skipping slideshow image {}: {}", slide.path.display(), e),
+                }
+            }
+            if let Some(slideshow) = &mut self.slideshow {
+                slideshow.shown(track, now);
+            }
+        }
+    }
+
+    /// Fills every output with `color` using a single-pixel buffer stretched
+    /// by the viewporter, or a 1x1 shm buffer when single-pixel buffers are
+    /// unsupported.
+    pub fn set_color(&mut self, color: Color, transition: &Transition) -> WallpaperResult<()> {
+        info!("Setting color: {:?}", color);
+
+        let mut event_queue = self
+            .event_queue
+            .take()
+            .expect("Event queue should be initialized");
+        let mut state = self
+            .wayland_state
+            .take()
+            .expect("Wayland state should be initialized");
+        // Put back even on failure, as the daemon keeps running.
+        let result = self.show_color(&mut event_queue, &mut state, color, transition);
+        self.event_queue = Some(event_queue);
+        self.wayland_state = Some(state);
+        result
+    }
+
+    fn show_color(
+        &mut self,
+        event_queue: &mut EventQueue<WaylandState>,
+        state: &mut WaylandState,
+        color: Color,
+        transition: &Transition,
+    ) -> WallpaperResult<()> {
+        let qh = event_queue.handle();
+        self.finish_transition(state, &qh);
+        let transition = &transition.resolve();
+        let previous_animation = self.animation.take();
+        let format = state.preferred_format();
+
+        let mut color_buffers = Vec::with_capacity(self.surface_ids.len());
+        let mut outputs = Vec::new();
+        for (i, &id) in self.surface_ids.iter().enumerate() {
+            let (buffer, pool) = match state.get_single_pixel_buffer_manager() {
+                Some(manager) => {
+                    debug!("Using single pixel buffer");
+                    let [r, g, b, a] = color.rgba().map(|c| c as u32 * 0x0101_0101);
+                    let buffer =
+                        manager.create_u32_rgba_buffer(r, g, b, a, &qh, BufferState::new());
+                    (Buffer::new(1, 1, buffer), None)
+                }
+                None => {
+                    debug!("Single pixel buffers unsupported, using 1x1 shm buffer");
+                    let mut pool = self.allocator.lock().allocate(1, 1, 4, format)?;
+                    pool.write_pixels(&color.rgba(), PixelOrder::Rgba);
+                    let buffer = pool.get_buffer(state.get_shm(), &qh).clone();
+                    (buffer, Some(pool))
+                }
+            };
+
+            let direct = if transition.is_animated() {
+                self.start_transitions(
+                    &[i],
+                    transition,
+                    |_| Target::Color {
+                        frame: solid_frame(color, &self.monitors[i], format),
+                        buffers: vec![buffer.clone()],
+                    },
+                    format,
+                    &mut outputs,
+                )?
+            } else {
+                vec![i]
+            };
+            if !direct.is_empty() {
+                if let Some(surface) = state.get_layer_surface(id) {
+                    buffer.set_released(false);
+                    surface.attach_buffer(&buffer, &qh);
+                }
+            }
+            color_buffers.push((buffer, pool));
+        }
+        self.shown.fill(Some(Shown::Color(color)));
+
+        self.current_wallpaper = RwLock::new(Some(Wallpaper::Color(color)));
+        if !outputs.is_empty() {
+            self.transition = Some(ActiveTransition::new(transition, outputs));
+        }
+        event_queue.roundtrip(state)?;
+        self.destroy_color_buffers(color_buffers);
+        if let Some(animation) = previous_animation {
+            self.recycle(animation.into_pools());
+        }
+        Ok(())
+    }
+
+    /// Starts an animated background drawn by `generator`. Its frames are
+    /// smaller than the outputs, so it cuts in rather than transitioning.
+    pub fn set_generated(&mut self, generator: Generator, dither: Dither) -> WallpaperResult<()> {
+        info!("Generating {:?} background", generator.kind);
+        let format = self.state().preferred_format();
+        let scene = GeneratorScene::new(generator.clone(), dither, format, &self.monitors);
+        self.start_animation(Box::new(scene), Wallpaper::Generated { generator, dither })
+    }
+
+    /// Plays `video` on every output, looping.
+    pub fn set_video(&mut self, video: Video) -> WallpaperResult<()> {
+        info!("Playing video {}", video.path.display());
+        let source = video.open()?;
+        let order = PixelOrder::native(self.state().preferred_format());
+        let scene = VideoScene::new(source, order, &self.monitors)?;
+        self.start_animation(Box::new(scene), Wallpaper::Video(video))
+    }
+
+    /// Shows the first frame of `scene` on every output, cutting from
+    /// whatever was there, and animates it from then on.
+    fn start_animation(
+        &mut self,
+        scene: Box<dyn Scene>,
+        wallpaper: Wallpaper,
+    ) -> WallpaperResult<()> {
+        let mut event_queue = self
+            .event_queue
+            .take()
+            .expect("Event queue should be initialized");
+        let mut state = self
+            .wayland_state
+            .take()
+            .expect("Wayland state should be initialized");
+        // Put back even on failure, as the daemon keeps running.
+        let result = self.show_animation(&mut event_queue, &mut state, scene, wallpaper);
         self.event_queue = Some(event_queue);
         self.wayland_state = Some(state);
+        result
+    }
+
+    fn show_animation(
+        &mut self,
+        event_queue: &mut EventQueue<WaylandState>,
+        state: &mut WaylandState,
+        scene: Box<dyn Scene>,
+        wallpaper: Wallpaper,
+    ) -> WallpaperResult<()> {
+        let qh = event_queue.handle();
+        self.finish_transition(state, &qh);
+        let format = state.preferred_format();
+
+        // Allocate everything first, so a failure leaves the outputs as
+        // they were.
+        let mut pools = Vec::with_capacity(self.surface_ids.len());
+        for i in 0..self.surface_ids.len() {
+            let (width, height) = scene.size(i);
+            let (width, height) = (width as i32, height as i32);
+            let pool = self
+                .allocator
+                .lock()
+                .allocate(width, height, width * 4, format);
+            match pool {
+                Ok(pool) => pools.push(pool),
+                Err(e) => {
+                    let mut allocator = self.allocator.lock();
+                    for pool in pools {
+                        allocator.recycle(pool);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        let previous_animation = self.animation.take();
+
+        let mut animated = Vec::with_capacity(self.surface_ids.len());
+        for ((i, &id), mut pool) in self.surface_ids.iter().enumerate().zip(pools) {
+            pool.fill_frame(|frame| {
+                scene.draw(i, Duration::ZERO, frame);
+                true
+            });
+            let pool = Arc::new(Mutex::new(pool));
+
+            if let Some(buffer) = pool.lock().acquire_buffer(state.get_shm(), &qh) {
+                if let Some(surface) = state.get_layer_surface(id) {
+                    surface.attach_buffer(&buffer, &qh);
+                }
+            }
+            self.shown[i] = Some(Shown::Pool(Arc::clone(&pool)));
+            animated.push(AnimatedOutput::new(i, vec![id], pool));
+        }
+
+        self.current_wallpaper = RwLock::new(Some(wallpaper));
+        self.animation = Some(Animation::new(scene, animated, self.animation_cpu_budget));
+        event_queue.roundtrip(state)?;
+        self.destroy_color_buffers(Vec::new());
+        if let Some(animation) = previous_animation {
+            self.recycle(animation.into_pools());
+        }
         Ok(())
     }
+
+    /// Replaces the buffers of the previous solid color with `buffers`,
+    /// destroying the old ones now that the surfaces show something else,
+    /// or once the running transition no longer blends away from them.
+    fn destroy_color_buffers(&mut self, buffers: Vec<(Buffer, Option<BufferPool>)>) {
+        let old = std::mem::replace(&mut self.color_buffers, buffers);
+        if self.transition.is_some() {
+            self.retired_color_buffers.extend(old);
+            return;
+        }
+        for (buffer, pool) in old {
+            self.destroy_color_buffer(buffer, pool);
+        }
+    }
+
+    fn destroy_color_buffer(&mut self, buffer: Buffer, pool: Option<BufferPool>) {
+        match pool {
+            // Pooled buffers are destroyed along with their pool.
+            Some(pool) => self.allocator.lock().recycle(pool),
+            None => buffer.buffer().destroy(),
+        }
+    }
 }
@@ -1,14 +1,22 @@
 use crate::{
-    core::buffer::Buffer,
+    core::{
+        buffer::Buffer,
+        damage::DamageRect,
+        presentation::{FeedbackData, FrameStats, FrameTiming},
+    },
     display::monitor::Monitor,
     utils::{error::WallpaperResult, wayland::WaylandState},
 };
 use log::debug;
+use std::time::{Duration, Instant};
 use wayland_client::{
     protocol::{wl_callback, wl_compositor, wl_surface},
     Connection, Proxy, QueueHandle,
 };
-use wayland_protocols::wp::viewporter::client::{wp_viewport, wp_viewporter};
+use wayland_protocols::wp::{
+    presentation_time::client::wp_presentation,
+    viewporter::client::{wp_viewport, wp_viewporter},
+};
 use wayland_protocols_wlr::layer_shell::v1::client::{
     zwlr_layer_shell_v1::{self, ZwlrLayerShellV1},
     zwlr_layer_surface_v1::{self, ZwlrLayerSurfaceV1},
@@ -19,10 +27,18 @@ pub struct LayerSurface {
     surface: wl_surface::WlSurface,
     layer: ZwlrLayerSurfaceV1,
     viewport: Option<wp_viewport::WpViewport>,
+    logical_size: (i32, i32),
     configured: bool,
+    /// Buffer attached before the first configure, committed once it arrives.
     pending_buffer: Option<Buffer>,
+    /// Buffer currently attached to the surface.
+    current_buffer: Option<Buffer>,
     frame_callback: Option<wl_callback::WlCallback>,
     frame_done: bool,
+    presentation: Option<wp_presentation::WpPresentation>,
+    /// When the next committed frame is meant to reach the screen.
+    present_target: Option<Instant>,
+    timing: FrameTiming,
 }
 
 impl LayerSurface {
@@ -33,6 +49,7 @@ impl LayerSurface {
         layer_shell: &ZwlrLayerShellV1,
         compositor: &wl_compositor::WlCompositor,
         viewporter: Option<&wp_viewporter::WpViewporter>,
+        presentation: Option<&wp_presentation::WpPresentation>,
     ) -> WallpaperResult<Self> {
         let surface = compositor.create_surface(qh, ());
         debug!("Created wayland surface: {:?}", surface.id());
@@ -70,34 +87,73 @@ impl LayerSurface {
             surface,
             layer,
             viewport,
+            logical_size: monitor.logical_size(),
             configured: false,
             pending_buffer: None,
+            current_buffer: None,
             frame_callback,
             frame_done: true,
+            presentation: presentation.cloned(),
+            present_target: None,
+            timing: FrameTiming::default(),
         })
     }
 
+    /// Attaches `buffer` and commits. The buffer must already be marked busy,
+    /// e.g. by `BufferPool::acquire_buffer`, so the same buffer can be shown
+    /// on several surfaces at once.
     pub fn attach_buffer(&mut self, buffer: &Buffer, qh: &QueueHandle<WaylandState>) {
-        debug!("Attempting to attach buffer {:?}", buffer.buffer().id());
+        self.attach_damaged(buffer, None, qh);
+    }
 
-        if !buffer.is_released() && buffer.release_count() < 2 {
-            debug!(
-                "Buffer not ready for attachment - released: {}, count: {}",
-                buffer.is_released(),
-                buffer.release_count()
-            );
+    /// Like `attach_buffer`, but only reports `damage` to the compositor.
+    /// `None` damages the whole buffer.
+    pub fn attach_damaged(
+        &mut self,
+        buffer: &Buffer,
+        damage: Option<&[DamageRect]>,
+        qh: &QueueHandle<WaylandState>,
+    ) {
+        debug!(
+            "Attaching buffer {:?} (releases so far: {})",
+            buffer.buffer().id(),
+            buffer.release_count()
+        );
+
+        if !self.configured {
+            debug!("Surface not configured yet, deferring attach");
+            self.pending_buffer = Some(buffer.clone());
             return;
         }
 
-        debug!("Buffer ready for attachment");
-        buffer.set_released(false);
-        self.pending_buffer = Some(buffer.clone());
+        self.current_buffer = Some(buffer.clone());
         self.surface.attach(Some(buffer.buffer()), 0, 0);
 
         if let Some(viewport) = &self.viewport {
-            let (width, height) = buffer.size();
+            let (width, height) = match self.logical_size {
+                (width, height) if width > 0 && height > 0 => (width, height),
+                _ => {
+                    let (width, height) = buffer.size();
+                    (width as i32, height as i32)
+                }
+            };
             debug!("Setting viewport destination: {}x{}", width, height);
-            viewport.set_destination(width as i32, height as i32);
+            viewport.set_destination(width, height);
+        }
+
+        // damage_buffer needs wl_surface v4; older surfaces get full damage in
+        // surface coordinates since the viewport scales the buffer.
+        match damage {
+            Some(rects) if self.surface.version() >= 4 => {
+                for rect in rects {
+                    self.surface
+                        .damage_buffer(rect.x, rect.y, rect.width, rect.height);
+                }
+            }
+            _ if self.surface.version() >= 4 => {
+                self.surface.damage_buffer(0, 0, i32::MAX, i32::MAX);
+            }
+            _ => self.surface.damage(0, 0, i32::MAX, i32::MAX),
         }
 
         if self.frame_done {
@@ -106,6 +162,17 @@ impl LayerSurface {
             self.frame_done = false;
         }
 
+        if let Some(presentation) = &self.presentation {
+            presentation.feedback(
+                &self.surface,
+                qh,
+                FeedbackData {
+                    surface: self.layer.id().protocol_id(),
+                    target: self.present_target.take(),
+                },
+            );
+        }
+
         debug!("Committing surface");
         self.surface.commit();
     }
@@ -131,17 +198,43 @@ impl LayerSurface {
         }
     }
 
-    pub fn handle_frame(
+    pub fn handle_frame(&mut self, _callback: &wl_callback::WlCallback) {
+        self.frame_done = true;
+    }
+
+    pub fn owns_frame_callback(&self, callback: &wl_callback::WlCallback) -> bool {
+        self.frame_callback
+            .as_ref()
+            .is_some_and(|c| c.id() == callback.id())
+    }
+
+    pub fn handle_presented(
         &mut self,
-        _callback: &wl_callback::WlCallback,
-        qh: &QueueHandle<WaylandState>,
+        time: Instant,
+        refresh: Option<Duration>,
+        target: Option<Instant>,
     ) {
-        self.frame_done = true;
-        if self.pending_buffer.is_some() {
-            self.frame_callback = Some(self.surface.frame(qh, ()));
-            self.frame_done = false;
-            self.surface.commit();
-        }
+        self.timing.presented(time, refresh, target);
+    }
+
+    pub fn handle_discarded(&mut self) {
+        self.timing.discarded();
+    }
+
+    /// The earliest time a frame committed `now` can reach the screen, if
+    /// presentation feedback has told us.
+    pub fn next_present(&self, now: Instant) -> Option<Instant> {
+        self.timing.next_present(now)
+    }
+
+    /// Marks the next commit as an animation frame meant for `target`, so
+    /// presenting it late counts as a dropped frame.
+    pub fn expect_present(&mut self, target: Instant) {
+        self.present_target = Some(target);
+    }
+
+    pub fn frame_stats(&self) -> FrameStats {
+        self.timing.stats()
     }
 
     pub fn is_draw_ready(&self) -> bool {
@@ -156,11 +249,7 @@ impl LayerSurface {
         self.configured = configured;
     }
 
-    pub fn take_pending_buffer(&mut self) -> Option<Buffer> {
-        self.pending_buffer.take()
-    }
-
-    pub fn needs_redraw(&self) -> bool {
-        self.pending_buffer.is_some() && self.frame_done
+    pub fn current_buffer(&self) -> Option<&Buffer> {
+        self.current_buffer.as_ref()
     }
 }
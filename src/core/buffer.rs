@@ -2,25 +2,57 @@ use std::sync::{
     atomic::{AtomicBool, AtomicU32, Ordering},
     Arc,
 };
-use wayland_client::protocol::wl_buffer;
+use wayland_client::{protocol::wl_buffer, Proxy};
+
+/// Release state shared between every `Buffer` handle and the `wl_buffer`'s
+/// user data, so a release event reaches exactly the buffer it belongs to.
+#[derive(Debug)]
+pub struct BufferState {
+    released: AtomicBool,
+    release_count: AtomicU32,
+}
+
+impl BufferState {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            released: AtomicBool::new(true),
+            release_count: AtomicU32::new(0),
+        })
+    }
+
+    pub fn set_released(&self, released: bool) {
+        self.released.store(released, Ordering::Release);
+        if released {
+            self.release_count.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.released.load(Ordering::Acquire)
+    }
+}
 
 #[derive(Clone)]
 pub struct Buffer {
     buffer: wl_buffer::WlBuffer,
     width: u32,
     height: u32,
-    released: Arc<AtomicBool>,
-    release_count: Arc<AtomicU32>,
+    state: Arc<BufferState>,
 }
 
 impl Buffer {
+    /// Wraps a `wl_buffer` created with an `Arc<BufferState>` as its user
+    /// data.
     pub fn new(width: u32, height: u32, buffer: wl_buffer::WlBuffer) -> Self {
+        let state = buffer
+            .data::<Arc<BufferState>>()
+            .cloned()
+            .expect("wl_buffer should carry a BufferState");
         Self {
             buffer,
             width,
             height,
-            released: Arc::new(AtomicBool::new(true)),
-            release_count: Arc::new(AtomicU32::new(0)),
+            state,
         }
     }
 
@@ -37,18 +69,15 @@ impl Buffer {
     }
 
     pub fn set_released(&self, released: bool) {
-        self.released.store(released, Ordering::Release);
-        if released {
-            self.release_count.fetch_add(1, Ordering::AcqRel);
-        }
+        self.state.set_released(released);
     }
 
     pub fn is_released(&self) -> bool {
-        self.released.load(Ordering::Acquire)
+        self.state.is_released()
     }
 
     pub fn release_count(&self) -> u32 {
-        self.release_count.load(Ordering::Acquire)
+        self.state.release_count.load(Ordering::Acquire)
     }
 
     pub fn size(&self) -> (u32, u32) {
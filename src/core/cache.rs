@@ -1,45 +1,328 @@
-use crate::core::buffer::Buffer;
-use std::collections::HashMap;
+use crate::{core::pool::BufferPool, image::source::SourceId};
+use log::debug;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, hash::Hash, sync::Arc};
 
-#[derive(Clone, Eq, Hash, PartialEq)]
+/// Everything a rendered frame depends on: the source file's identity, the
+/// output size and shm format, and a tag hashing the render parameters.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
 pub struct CacheKey {
-    path: String,
+    source: SourceId,
     width: u32,
     height: u32,
+    format: u32,
+    tag: u64,
 }
 
 impl CacheKey {
-    pub fn new(path: &str, width: u32, height: u32) -> Self {
+    pub fn new(source: &SourceId, width: u32, height: u32, format: u32, tag: u64) -> Self {
         Self {
-            path: path.to_string(),
+            source: source.clone(),
             width,
             height,
+            format,
+            tag,
         }
     }
+
+    pub fn source(&self) -> &SourceId {
+        &self.source
+    }
 }
 
-pub struct Cache {
-    buffers: HashMap<CacheKey, Buffer>,
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub budget: usize,
+    /// Bytes of pools kept for reuse, which share the budget.
+    pub spare: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} entries, {:.1}/{:.1} MiB, {} hits, {} misses, {} evictions",
+            self.entries,
+            self.bytes as f64 / (1024.0 * 1024.0),
+            self.budget as f64 / (1024.0 * 1024.0),
+            self.hits,
+            self.misses,
+            self.evictions
+        )?;
+        if self.spare > 0 {
+            write!(
+                f,
+                ", {:.1} MiB spare",
+                self.spare as f64 / (1024.0 * 1024.0)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct LruEntry<V> {
+    value: V,
+    bytes: usize,
+    last_used: u64,
+}
+
+/// A map that evicts its least recently used entries once the byte sizes
+/// given on insert exceed `budget`.
+pub struct Lru<K, V> {
+    entries: HashMap<K, LruEntry<V>>,
+    budget: usize,
+    bytes: usize,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
 }
 
-impl Default for Cache {
-    fn default() -> Self {
-        Self::new()
+impl<K: Eq + Hash + Clone, V> Lru<K, V> {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            budget,
+            bytes: 0,
+            clock: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.hits += 1;
+                entry.last_used = self.clock;
+                Some(&entry.value)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Inserts `value` and returns whatever had to be evicted to stay within
+    /// budget. An entry larger than the whole budget is still kept until the
+    /// next insert, so the value just rendered is never dropped immediately.
+    pub fn insert(&mut self, key: K, value: V, bytes: usize) -> Vec<V> {
+        self.clock += 1;
+        let mut evicted = Vec::new();
+        if let Some(old) = self.entries.remove(&key) {
+            self.bytes -= old.bytes;
+            evicted.push(old.value);
+        }
+
+        evicted.extend(self.evict_to(self.budget.saturating_sub(bytes)));
+        self.bytes += bytes;
+        self.entries.insert(
+            key,
+            LruEntry {
+                value,
+                bytes,
+                last_used: self.clock,
+            },
+        );
+        evicted
+    }
+
+    /// Removes every entry whose key fails `keep`, without counting it as an
+    /// eviction.
+    pub fn retain(&mut self, mut keep: impl FnMut(&K) -> bool) -> Vec<V> {
+        let stale: Vec<K> = self.entries.keys().filter(|k| !keep(k)).cloned().collect();
+        stale
+            .into_iter()
+            .filter_map(|key| self.entries.remove(&key))
+            .map(|entry| {
+                self.bytes -= entry.bytes;
+                entry.value
+            })
+            .collect()
+    }
+
+    pub fn clear(&mut self) -> Vec<V> {
+        self.bytes = 0;
+        self.entries.drain().map(|(_, entry)| entry.value).collect()
+    }
+
+    /// Changes the budget, returning whatever had to be evicted to fit in
+    /// a smaller one.
+    pub fn set_budget(&mut self, budget: usize) -> Vec<V> {
+        self.budget = budget;
+        self.evict_to(budget)
+    }
+
+    /// Evicts least recently used entries until at most `bytes` remain.
+    fn evict_to(&mut self, bytes: usize) -> Vec<V> {
+        let mut evicted = Vec::new();
+        while self.bytes > bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.bytes -= entry.bytes;
+            self.evictions += 1;
+            evicted.push(entry.value);
+        }
+        evicted
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            bytes: self.bytes,
+            budget: self.budget,
+            spare: 0,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+        }
     }
 }
 
+/// Rendered wallpapers, each kept in the pool it was written to so a busy
+/// buffer can be swapped for a free slot holding the same frame. Pools that
+/// leave the cache are handed back to the caller to recycle.
+pub struct Cache {
+    pools: Lru<CacheKey, Arc<Mutex<BufferPool>>>,
+}
+
 impl Cache {
-    pub fn new() -> Self {
+    pub fn new(budget: usize) -> Self {
         Self {
-            buffers: HashMap::new(),
+            pools: Lru::new(budget),
+        }
+    }
+
+    pub fn get(&mut self, key: &CacheKey) -> Option<&Arc<Mutex<BufferPool>>> {
+        self.pools.get(key)
+    }
+
+    /// Inserts a rendered pool, dropping any frames of older versions of the
+    /// same file since they can never be hit again.
+    ///
+    /// Returns the pools that left the cache so their memory can be reused.
+    pub fn insert(
+        &mut self,
+        key: CacheKey,
+        pool: Arc<Mutex<BufferPool>>,
+    ) -> Vec<Arc<Mutex<BufferPool>>> {
+        let mut stale = self
+            .pools
+            .retain(|cached| !key.source().supersedes(cached.source()));
+        if !stale.is_empty() {
+            debug!(
+                "Dropped {} buffer pools of a changed {}",
+                stale.len(),
+                key.source().path
+            );
+        }
+
+        let bytes = pool.lock().size();
+        let evicted = self.pools.insert(key, pool, bytes);
+        if !evicted.is_empty() {
+            debug!("Evicted {} buffer pools from cache", evicted.len());
         }
+        stale.extend(evicted);
+        stale
+    }
+
+    pub fn clear(&mut self) -> Vec<Arc<Mutex<BufferPool>>> {
+        let evicted = self.pools.clear();
+        debug!("Cleared {} buffer pools from cache", evicted.len());
+        evicted
     }
 
-    pub fn get(&self, key: &CacheKey) -> Option<&Buffer> {
-        self.buffers.get(key)
+    pub fn stats(&self) -> CacheStats {
+        self.pools.stats()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lru(entries: &[(&'static str, usize)], budget: usize) -> Lru<&'static str, &'static str> {
+        let mut lru = Lru::new(budget);
+        for &(key, bytes) in entries {
+            assert!(lru.insert(key, key, bytes).is_empty());
+        }
+        lru
+    }
+
+    fn sorted(mut values: Vec<&'static str>) -> Vec<&'static str> {
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let mut lru = lru(&[("a", 10), ("b", 10), ("c", 10)], 30);
+        assert_eq!(lru.get(&"a"), Some(&"a"));
+        assert_eq!(lru.insert("d", "d", 20), ["b", "c"]);
+        assert_eq!(lru.get(&"b"), None);
+        assert_eq!(lru.get(&"a"), Some(&"a"));
+
+        let stats = lru.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.budget), (2, 30, 30));
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 1, 2));
+    }
+
+    #[test]
+    fn replacing_a_key_returns_the_old_value() {
+        let mut lru = lru(&[("a", 10)], 30);
+        assert_eq!(lru.insert("a", "new", 25), ["a"]);
+        assert_eq!(lru.get(&"a"), Some(&"new"));
+        let stats = lru.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (1, 25, 0));
+    }
+
+    #[test]
+    fn keeps_an_oversized_entry_until_the_next_insert() {
+        let mut lru = lru(&[("a", 10), ("b", 10)], 30);
+        assert_eq!(sorted(lru.insert("big", "big", 50)), ["a", "b"]);
+        assert_eq!(lru.get(&"big"), Some(&"big"));
+        assert_eq!(lru.stats().bytes, 50);
+
+        assert_eq!(lru.insert("c", "c", 10), ["big"]);
+        assert_eq!(lru.stats().bytes, 10);
+    }
+
+    #[test]
+    fn lowering_the_budget_evicts() {
+        let mut lru = lru(&[("a", 10), ("b", 10), ("c", 10)], 30);
+        assert!(lru.set_budget(40).is_empty());
+        assert_eq!(lru.set_budget(15), ["a", "b"]);
+        let stats = lru.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.budget), (1, 10, 15));
+        assert_eq!(lru.set_budget(0), ["c"]);
+        assert_eq!(lru.stats().bytes, 0);
+    }
+
+    #[test]
+    fn retain_and_clear_drop_entries_without_evicting() {
+        let mut lru = lru(&[("a", 10), ("b", 5), ("c", 7)], 30);
+        assert_eq!(lru.retain(|key| *key != "b"), ["b"]);
+        assert_eq!(lru.stats().bytes, 17);
 
-    pub fn insert(&mut self, key: CacheKey, buffer: Buffer) {
-        self.buffers.insert(key, buffer);
+        assert_eq!(sorted(lru.clear()), ["a", "c"]);
+        let stats = lru.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (0, 0, 0));
+        assert!(lru.insert("d", "d", 30).is_empty());
     }
 }
@@ -1,12 +1,31 @@
 use crate::{
-    core::ipc::{IpcMessage, IpcServer},
+    core::ipc::{IpcMessage, IpcResponse, IpcServer},
+    image::loader::ImageLoader,
+    utils::{config::Config, error::WallpaperError},
     App, WallpaperResult,
 };
+use log::{error, warn};
 use parking_lot::Mutex;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    net::UnixStream,
+    sync::{mpsc, oneshot},
+    task::JoinSet,
+    time::{sleep_until, timeout, Duration, Instant},
+};
+
+/// How long a client gets to send its whole request.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A decoded request and where its response goes.
+type Request = (IpcMessage, oneshot::Sender<IpcResponse>);
 
 pub struct Daemon {
     running: Arc<AtomicBool>,
@@ -15,8 +34,9 @@ pub struct Daemon {
 }
 
 impl Daemon {
-    pub async fn new() -> WallpaperResult<Self> {
-        let app = App::new()?;
+    pub async fn new(config: Config) -> WallpaperResult<Self> {
+        ImageLoader::set_cache_budget(config.image_cache_budget);
+        let app = App::new(&config)?;
 
         Ok(Self {
             running: Arc::new(AtomicBool::new(true)),
@@ -25,20 +45,184 @@ impl Daemon {
         })
     }
 
+    /// Serves requests while dispatching Wayland events, which drive
+    /// transitions through frame callbacks.
     pub async fn run(&self) -> WallpaperResult<()> {
-        while self.running.load(Ordering::Relaxed) {
-            let (_, msg) = self.server.accept().await?;
-            let mut app = self.app.lock();
+        let fd = self.app.lock().connection_fd();
+        let wayland = AsyncFd::with_interest(fd, Interest::READABLE)?;
+
+        let (requests, mut incoming) = mpsc::unbounded_channel();
+        let mut clients = JoinSet::new();
 
-            match msg {
-                IpcMessage::SetWallpaper { image, monitor: _ } => {
-                    app.set_wallpaper_and_exit(image.to_str().unwrap())?;
+        while self.running.load(Ordering::Relaxed) {
+            let deadline = self.app.lock().next_deadline();
+            tokio::select! {
+                stream = self.server.accept() => match stream {
+                    Ok(stream) => {
+                        clients.spawn(serve(stream, requests.clone()));
+                    }
+                    Err(e) => warn!("Failed to accept a client: {}", e),
+                },
+                Some((msg, reply)) = incoming.recv() => {
+                    let response = self.handle(msg).unwrap_or_else(|e| {
+                        error!("Request failed: {}", e);
+                        IpcResponse::Error(e.to_string())
+                    });
+                    // Clients that disconnect early just miss the response.
+                    let _ = reply.send(response);
                 }
-                IpcMessage::StopDaemon => {
-                    self.running.store(false, Ordering::Relaxed);
+                Some(_) = clients.join_next(), if !clients.is_empty() => {}
+                guard = wayland.readable() => {
+                    let mut guard = guard?;
+                    if let Ok(result) = guard.try_io(|_| self.app.lock().read_events()) {
+                        result?;
+                    }
                 }
+                _ = sleep_until(Instant::from_std(deadline.unwrap_or_else(std::time::Instant::now))),
+                    if deadline.is_some() => {}
             }
+            self.app.lock().dispatch()?;
         }
+
+        // Let clients still connected, such as the one that asked us to
+        // stop, get their responses.
+        drop(incoming);
+        let _ = timeout(RECEIVE_TIMEOUT, async {
+            while clients.join_next().await.is_some() {}
+        })
+        .await;
         Ok(())
     }
+
+    fn handle(&self, msg: IpcMessage) -> WallpaperResult<IpcResponse> {
+        let mut app = self.app.lock();
+        // Colors and animations always cover every output.
+        if let IpcMessage::SetColor {
+            monitor: Some(monitor),
+            ..
+        }
+        | IpcMessage::SetGenerated {
+            monitor: Some(monitor),
+            ..
+        }
+        | IpcMessage::SetVideo {
+            monitor: Some(monitor),
+            ..
+        } = &msg
+        {
+            return Err(WallpaperError::InvalidMonitor(format!(
+                "{monitor}: only images can be set on a single output"
+            )));
+        }
+        // Setting a wallpaper by hand ends the slideshow.
+        if matches!(
+            msg,
+            IpcMessage::SetWallpaper { .. }
+                | IpcMessage::SetColor { .. }
+                | IpcMessage::SetGenerated { .. }
+                | IpcMessage::SetVideo { .. }
+        ) {
+            app.stop_slideshow();
+        }
+
+        match msg {
+            IpcMessage::SetWallpaper {
+                image,
+                monitor,
+                mode,
+                dither,
+                ken_burns,
+                transition,
+            } => {
+                let path = image.to_str().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{}: path is not valid UTF-8", image.display()),
+                    )
+                })?;
+                app.set_wallpaper_and_exit(
+                    monitor.as_deref(),
+                    path,
+                    &mode,
+                    dither,
+                    ken_burns,
+                    &transition,
+                )?;
+            }
+            IpcMessage::SetColor {
+                color,
+                // Rejected above when set.
+                monitor: _,
+                transition,
+            } => {
+                app.set_color(color, &transition)?;
+            }
+            IpcMessage::SetGenerated {
+                generator,
+                monitor: _,
+                dither,
+            } => {
+                app.set_generated(generator, dither)?;
+            }
+            IpcMessage::SetVideo { video, monitor: _ } => {
+                app.set_video(video)?;
+            }
+            IpcMessage::StartSlideshow(slideshow) => {
+                app.start_slideshow(slideshow)?;
+            }
+            IpcMessage::StopSlideshow => {
+                app.stop_slideshow();
+            }
+            IpcMessage::SlideshowStatus => {
+                return Ok(IpcResponse::Slideshow(app.slideshow_status()));
+            }
+            IpcMessage::CacheStats => {
+                return Ok(IpcResponse::CacheStats {
+                    buffers: app.cache_stats(),
+                    images: ImageLoader::cache_stats(),
+                    disk: app.disk_cache_stats(),
+                });
+            }
+            IpcMessage::FrameStats => {
+                return Ok(IpcResponse::FrameStats(app.frame_stats()));
+            }
+            IpcMessage::CacheClear => {
+                app.clear_cache();
+                ImageLoader::clear_cache();
+            }
+            IpcMessage::StopDaemon => {
+                self.running.store(false, Ordering::Relaxed);
+            }
+        }
+        Ok(IpcResponse::Ok)
+    }
+}
+
+/// Reads one client's request off the main loop, so slow clients can't
+/// stall frames, and writes back the loop's response.
+async fn serve(mut stream: UnixStream, requests: mpsc::UnboundedSender<Request>) {
+    let response = match timeout(RECEIVE_TIMEOUT, IpcServer::receive(&mut stream)).await {
+        Ok(Ok(msg)) => {
+            let (reply, response) = oneshot::channel();
+            if requests.send((msg, reply)).is_err() {
+                return;
+            }
+            match response.await {
+                Ok(response) => response,
+                Err(_) => return,
+            }
+        }
+        Ok(Err(e)) => {
+            error!("Request failed: {}", e);
+            IpcResponse::Error(e.to_string())
+        }
+        Err(_) => {
+            warn!(
+                "Dropping a client that sent no request within {:?}",
+                RECEIVE_TIMEOUT
+            );
+            return;
+        }
+    };
+    let _ = IpcServer::respond(&mut stream, &response).await;
 }
@@ -0,0 +1,221 @@
+/// A rectangle in buffer pixels that changed between two frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DamageRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl DamageRect {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn full(width: i32, height: i32) -> Self {
+        Self::new(0, 0, width, height)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    /// The part of the rectangle inside a `width`x`height` buffer.
+    pub fn clip(&self, width: i32, height: i32) -> Self {
+        let x = self.x.clamp(0, width);
+        let y = self.y.clamp(0, height);
+        Self {
+            x,
+            y,
+            width: (self.x + self.width).clamp(0, width) - x,
+            height: (self.y + self.height).clamp(0, height) - y,
+        }
+    }
+
+    pub fn contains(&self, other: &Self) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
+}
+
+/// Damage a pool slot has missed since it was last written.
+///
+/// Every frame is drawn into whichever slot is free, so a slot that sat out
+/// a frame must catch up on that frame's damage on top of its own before it
+/// is shown again.
+#[derive(Debug, Default)]
+pub struct SlotDamage {
+    rects: Vec<DamageRect>,
+}
+
+/// Past this many rectangles the slot is simply redrawn in full.
+const MAX_RECTS: usize = 32;
+
+impl SlotDamage {
+    pub fn add(&mut self, rect: DamageRect) {
+        if rect.is_empty() || self.rects.iter().any(|r| r.contains(&rect)) {
+            return;
+        }
+        self.rects.retain(|r| !rect.contains(r));
+        self.rects.push(rect);
+    }
+
+    pub fn take(&mut self, width: i32, height: i32) -> Vec<DamageRect> {
+        if self.rects.len() > MAX_RECTS {
+            self.rects.clear();
+            return vec![DamageRect::full(width, height)];
+        }
+        self.rects
+            .drain(..)
+            .map(|r| r.clip(width, height))
+            .filter(|r| !r.is_empty())
+            .collect()
+    }
+
+    pub fn reset(&mut self, rect: DamageRect) {
+        self.rects.clear();
+        self.add(rect);
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+}
+
+/// What each slot of a pool has missed, so a frame drawn into one slot only
+/// has to redraw what changed since that slot was last written.
+#[derive(Debug)]
+pub struct PoolDamage {
+    slots: Vec<SlotDamage>,
+}
+
+impl PoolDamage {
+    /// `count` slots that all still need a full `width`x`height` frame.
+    pub fn new(count: usize, width: i32, height: i32) -> Self {
+        let mut damage = Self {
+            slots: (0..count).map(|_| SlotDamage::default()).collect(),
+        };
+        damage.reset(width, height);
+        damage
+    }
+
+    pub fn reset(&mut self, width: i32, height: i32) {
+        for slot in &mut self.slots {
+            slot.reset(DamageRect::full(width, height));
+        }
+    }
+
+    /// Records that `rect` of `slot` was written, leaving it up to date and
+    /// every other slot missing `rect`.
+    pub fn written(&mut self, slot: usize, rect: DamageRect) {
+        for (i, damage) in self.slots.iter_mut().enumerate() {
+            if i == slot {
+                damage.clear();
+            } else {
+                damage.add(rect);
+            }
+        }
+    }
+
+    /// Records that `slot` now holds a copy of the up to date frame.
+    pub fn copied(&mut self, slot: usize) {
+        self.slots[slot].clear();
+    }
+
+    /// Records a frame whose `damage` is about to be drawn into `slot` and
+    /// returns what `slot` has to redraw: that damage plus whatever it
+    /// missed while other slots were drawn.
+    pub fn draw(
+        &mut self,
+        slot: usize,
+        damage: &[DamageRect],
+        width: i32,
+        height: i32,
+    ) -> Vec<DamageRect> {
+        for rect in damage {
+            for pending in &mut self.slots {
+                pending.add(*rect);
+            }
+        }
+        self.slots[slot].take(width, height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn up_to_date(width: i32, height: i32) -> PoolDamage {
+        let mut damage = PoolDamage::new(2, width, height);
+        damage.written(0, DamageRect::full(width, height));
+        damage.copied(1);
+        damage
+    }
+
+    #[test]
+    fn new_slots_redraw_in_full() {
+        let mut damage = PoolDamage::new(2, 8, 8);
+        let small = [DamageRect::new(1, 1, 2, 2)];
+        assert_eq!(damage.draw(0, &small, 8, 8), [DamageRect::full(8, 8)]);
+        assert_eq!(damage.draw(1, &small, 8, 8), [DamageRect::full(8, 8)]);
+    }
+
+    #[test]
+    fn damage_accumulates_across_slots() {
+        let mut damage = up_to_date(16, 16);
+        let a = DamageRect::new(0, 0, 4, 4);
+        let b = DamageRect::new(8, 8, 4, 4);
+        let c = DamageRect::new(0, 12, 2, 2);
+
+        assert_eq!(damage.draw(0, &[a], 16, 16), [a]);
+        // Slot 1 missed `a` while slot 0 was shown.
+        assert_eq!(damage.draw(1, &[b], 16, 16), [a, b]);
+        assert_eq!(damage.draw(0, &[c], 16, 16), [b, c]);
+        // Nothing new: each slot still catches up on the other's frame.
+        assert_eq!(damage.draw(1, &[], 16, 16), [c]);
+        assert!(damage.draw(0, &[], 16, 16).is_empty());
+    }
+
+    #[test]
+    fn full_writes_and_copies_settle_slots() {
+        let mut damage = up_to_date(16, 16);
+        damage.draw(0, &[DamageRect::new(0, 0, 4, 4)], 16, 16);
+        damage.copied(1);
+        assert!(damage.draw(1, &[], 16, 16).is_empty());
+
+        damage.written(1, DamageRect::full(16, 16));
+        assert_eq!(damage.draw(0, &[], 16, 16), [DamageRect::full(16, 16)]);
+        assert!(damage.draw(1, &[], 16, 16).is_empty());
+    }
+
+    #[test]
+    fn contained_rects_merge_and_overflow_redraws_all() {
+        let mut damage = up_to_date(64, 64);
+        let big = DamageRect::new(0, 0, 32, 32);
+        let rects = [
+            DamageRect::new(2, 2, 4, 4),
+            big,
+            DamageRect::new(8, 8, 2, 2),
+        ];
+        assert_eq!(damage.draw(0, &rects, 64, 64), [big]);
+
+        let many: Vec<_> = (0..=MAX_RECTS as i32)
+            .map(|i| DamageRect::new(i, i, 1, 1))
+            .collect();
+        assert_eq!(damage.draw(0, &many, 64, 64), [DamageRect::full(64, 64)]);
+    }
+
+    #[test]
+    fn rects_are_clipped_to_the_frame() {
+        let mut damage = up_to_date(8, 8);
+        let rects = [DamageRect::new(-2, 6, 4, 4), DamageRect::new(9, 0, 2, 2)];
+        assert_eq!(damage.draw(0, &rects, 8, 8), [DamageRect::new(0, 6, 2, 2)]);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::{
+    core::cache::{CacheKey, CacheStats},
+    utils::{error::WallpaperResult, hash::StableHasher},
+};
+use log::{debug, warn};
+use std::{
+    env, fs,
+    hash::Hasher,
+    iter,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+const MAGIC: &[u8; 4] = b"WPFC";
+/// Bumped whenever the entry layout or the meaning of a key changes.
+const VERSION: u32 = 2;
+const EXTENSION: &str = "frame";
+/// How many frames may wait for the writer before new ones are dropped.
+const QUEUE_LEN: usize = 2;
+
+/// Work for the writer thread, done in order.
+enum Job {
+    Store(CacheKey, Vec<u8>),
+    /// Removes every entry, then reports back.
+    Clear(Sender<()>),
+}
+
+/// Rendered frames kept on disk across restarts, LZ4-compressed and in the
+/// shm format they are uploaded in, so a hit skips both decoding and scaling.
+///
+/// Each entry holds the serialized `CacheKey` it was stored under, the frame
+/// length and a CRC32 of the frame, and is rejected and deleted if any of
+/// them don't match. Entries are written by a background thread so storing
+/// never holds up rendering.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: Arc<AtomicU64>,
+    writer: SyncSender<Job>,
+}
+
+impl DiskCache {
+    pub fn new(max_bytes: u64, max_age: Duration) -> WallpaperResult<Self> {
+        let dir = default_dir();
+        fs::create_dir_all(&dir)?;
+        debug!("Using disk cache at {}", dir.display());
+
+        let writer = Writer {
+            dir: dir.clone(),
+            max_bytes,
+            max_age,
+            evictions: Arc::new(AtomicU64::new(0)),
+        };
+        writer.evict();
+        let evictions = Arc::clone(&writer.evictions);
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        thread::Builder::new()
+            .name("disk-cache".to_string())
+            .spawn(move || writer.run(receiver))?;
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions,
+            writer: sender,
+        })
+    }
+
+    /// Decompresses the frame stored for `key` straight into `dst`, returning
+    /// whether it was found intact.
+    pub fn load(&self, key: &CacheKey, dst: &mut [u8]) -> bool {
+        let start = Instant::now();
+        let Ok(key) = bincode::serialize(key) else {
+            return false;
+        };
+        let path = entry_path(&self.dir, &key);
+        let Ok(data) = fs::read(&path) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return false;
+        };
+
+        if let Err(reason) = decode_entry(&data, &key, dst) {
+            warn!("Discarding disk cache entry {}: {}", path.display(), reason);
+            let _ = fs::remove_file(&path);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        // Entries age from their last use, not their creation.
+        if let Ok(file) = fs::File::options().append(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "Loaded {} from disk cache in {:?}",
+            path.display(),
+            start.elapsed()
+        );
+        true
+    }
+
+    /// Queues `frame` to be stored under `key`, or drops it if the writer
+    /// is behind. Entries past the size and age limits are evicted once the
+    /// queue runs empty.
+    pub fn store(&self, key: CacheKey, frame: Vec<u8>) {
+        match self.writer.try_send(Job::Store(key, frame)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("Disk cache writer is behind, dropping entry"),
+            Err(TrySendError::Disconnected(_)) => {
+                warn!("Disk cache writer has stopped, dropping entry")
+            }
+        }
+    }
+
+    /// Removes every entry, dropping writes still queued so they don't
+    /// reappear afterwards.
+    pub fn clear(&self) {
+        let (done, cleared) = mpsc::channel();
+        if self.writer.send(Job::Clear(done)).is_err() || cleared.recv().is_err() {
+            clear(&self.dir);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = entries(&self.dir);
+        CacheStats {
+            entries: entries.len(),
+            bytes: entries.iter().map(|(_, len, _)| *len as usize).sum(),
+            budget: self.max_bytes as usize,
+            spare: 0,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The disk side of a `DiskCache`, run on its own thread.
+struct Writer {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    evictions: Arc<AtomicU64>,
+}
+
+impl Writer {
+    /// Runs queued jobs until the cache is dropped, evicting after each
+    /// burst rather than after every entry.
+    fn run(self, receiver: Receiver<Job>) {
+        while let Ok(job) = receiver.recv() {
+            let jobs: Vec<_> = iter::once(job).chain(receiver.try_iter()).collect();
+            // A clear makes the stores queued before it moot.
+            let first = jobs
+                .iter()
+                .rposition(|job| matches!(job, Job::Clear(_)))
+                .unwrap_or(0);
+            for job in jobs.into_iter().skip(first) {
+                match job {
+                    Job::Store(key, frame) => {
+                        if let Err(e) = self.store(&key, &frame) {
+                            warn!("Failed to write disk cache entry: {}", e);
+                        }
+                    }
+                    Job::Clear(done) => {
+                        clear(&self.dir);
+                        let _ = done.send(());
+                    }
+                }
+            }
+            self.evict();
+        }
+    }
+
+    fn store(&self, key: &CacheKey, frame: &[u8]) -> WallpaperResult<()> {
+        let start = Instant::now();
+        let key = bincode::serialize(key)?;
+        let entry = encode_entry(&key, frame);
+
+        // Write to a temporary file first so readers never see half an
+        // entry. No fsync: a torn entry after a crash fails its checksum.
+        let path = entry_path(&self.dir, &key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        if let Err(e) = fs::write(&tmp, &entry).and_then(|()| fs::rename(&tmp, &path)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+
+        debug!(
+            "Stored {} in disk cache ({} -> {} bytes) in {:?}",
+            path.display(),
+            frame.len(),
+            entry.len(),
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Removes entries unused for longer than the maximum age, then the
+    /// least recently used ones until the rest fit in the size limit, along
+    /// with temporary files a crash left behind.
+    fn evict(&self) {
+        remove_temp_files(&self.dir);
+        let mut entries = entries(&self.dir);
+        entries.sort_by_key(|(_, _, modified)| *modified);
+
+        let now = SystemTime::now();
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        for (path, len, modified) in entries {
+            let expired = now
+                .duration_since(modified)
+                .is_ok_and(|age| age > self.max_age);
+            if !expired && total <= self.max_bytes {
+                continue;
+            }
+            if fs::remove_file(&path).is_ok() {
+                debug!("Evicted {} from disk cache", path.display());
+                total -= len;
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn entry_path(dir: &Path, key: &[u8]) -> PathBuf {
+    // The name has to be stable across builds.
+    let mut hasher = StableHasher::default();
+    hasher.write(key);
+    dir.join(format!("{:016x}.{EXTENSION}", hasher.finish()))
+}
+
+fn entries(dir: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    let Ok(dir) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    dir.filter_map(Result::ok)
+        .filter(|entry| entry.path().extension().is_some_and(|e| e == EXTENSION))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((entry.path(), metadata.len(), metadata.modified().ok()?))
+        })
+        .collect()
+}
+
+fn clear(dir: &Path) {
+    for (path, _, _) in entries(dir) {
+        let _ = fs::remove_file(path);
+    }
+    remove_temp_files(dir);
+}
+
+/// Removes `<hash>.tmp<pid>` files. Only the writer thread creates them, so
+/// none are in flight while it runs this.
+fn remove_temp_files(dir: &Path) {
+    let Ok(dir) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in dir.filter_map(Result::ok) {
+        let path = entry.path();
+        if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.starts_with("tmp"))
+        {
+            debug!("Removing stale {}", path.display());
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// `$XDG_CACHE_HOME/wallpaper`, falling back to `~/.cache/wallpaper`.
+fn default_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("wallpaper")
+}
+
+fn encode_entry(key: &[u8], frame: &[u8]) -> Vec<u8> {
+    let compressed = lz4_flex::block::compress(frame);
+
+    let mut entry = Vec::with_capacity(24 + key.len() + compressed.len());
+    entry.extend_from_slice(MAGIC);
+    entry.extend_from_slice(&VERSION.to_le_bytes());
+    entry.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    entry.extend_from_slice(key);
+    entry.extend_from_slice(&(frame.len() as u64).to_le_bytes());
+    entry.extend_from_slice(&crc32fast::hash(frame).to_le_bytes());
+    entry.extend_from_slice(&compressed);
+    entry
+}
+
+fn decode_entry(data: &[u8], key: &[u8], dst: &mut [u8]) -> Result<(), &'static str> {
+    let mut rest = data;
+    let mut take = |len: usize| {
+        if rest.len() < len {
+            return Err("truncated");
+        }
+        let (head, tail) = rest.split_at(len);
+        rest = tail;
+        Ok(head)
+    };
+    let u32_at = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+
+    if take(4)? != MAGIC || u32_at(take(4)?) != VERSION {
+        return Err("unknown format");
+    }
+    let key_len = u32_at(take(4)?) as usize;
+    if take(key_len)? != key {
+        return Err("key mismatch");
+    }
+    let frame_len = u64::from_le_bytes(take(8)?.try_into().unwrap());
+    if frame_len != dst.len() as u64 {
+        return Err("frame size mismatch");
+    }
+    let crc = u32_at(take(4)?);
+
+    match lz4_flex::block::decompress_into(rest, dst) {
+        Ok(len) if len == dst.len() => {}
+        _ => return Err("corrupt payload"),
+    }
+    if crc32fast::hash(dst) != crc {
+        return Err("checksum mismatch");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"some serialized key";
+
+    fn frame() -> Vec<u8> {
+        (0..4096u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn decode(entry: &[u8], key: &[u8], len: usize) -> Result<Vec<u8>, &'static str> {
+        let mut dst = vec![0; len];
+        decode_entry(entry, key, &mut dst).map(|()| dst)
+    }
+
+    #[test]
+    fn round_trips() {
+        let frame = frame();
+        let entry = encode_entry(KEY, &frame);
+        assert_eq!(decode(&entry, KEY, frame.len()), Ok(frame));
+    }
+
+    #[test]
+    fn rejects_truncated_entries() {
+        let frame = frame();
+        let entry = encode_entry(KEY, &frame);
+        let header = 4 + 4 + 4 + KEY.len() + 8 + 4;
+        for len in [0, 3, 10, header - 1] {
+            assert_eq!(decode(&entry[..len], KEY, frame.len()), Err("truncated"));
+        }
+        assert_eq!(
+            decode(&entry[..entry.len() - 1], KEY, frame.len()),
+            Err("corrupt payload")
+        );
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let frame = frame();
+        let mut entry = encode_entry(KEY, &frame);
+        entry[0] = b'X';
+        assert_eq!(decode(&entry, KEY, frame.len()), Err("unknown format"));
+
+        let mut entry = encode_entry(KEY, &frame);
+        entry[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(decode(&entry, KEY, frame.len()), Err("unknown format"));
+    }
+
+    #[test]
+    fn rejects_other_keys_and_sizes() {
+        let frame = frame();
+        let entry = encode_entry(KEY, &frame);
+        assert_eq!(
+            decode(&entry, b"another serialized key", frame.len()),
+            Err("key mismatch")
+        );
+        assert_eq!(
+            decode(&entry, b"some serialized kex", frame.len()),
+            Err("key mismatch")
+        );
+        assert_eq!(
+            decode(&entry, KEY, frame.len() - 1),
+            Err("frame size mismatch")
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_payloads() {
+        let frame = frame();
+        let mut entry = encode_entry(KEY, &frame);
+        // The last literal bytes come through LZ4 unchanged, so flipping one
+        // still decompresses and only the checksum catches it.
+        *entry.last_mut().unwrap() ^= 1;
+        assert_eq!(decode(&entry, KEY, frame.len()), Err("checksum mismatch"));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::utils::error::WallpaperError;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Maps the linear progress of a transition to how far along it looks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Easing {
+    #[default]
+    Linear,
+    /// A CSS-style cubic Bézier from (0, 0) to (1, 1) through two control
+    /// points. The x coordinates must be in `[0, 1]`.
+    CubicBezier(f32, f32, f32, f32),
+    /// A lightly damped spring that overshoots once before settling.
+    Spring,
+}
+
+impl Easing {
+    pub const EASE: Self = Self::CubicBezier(0.25, 0.1, 0.25, 1.0);
+    pub const EASE_IN: Self = Self::CubicBezier(0.42, 0.0, 1.0, 1.0);
+    pub const EASE_OUT: Self = Self::CubicBezier(0.0, 0.0, 0.58, 1.0);
+    pub const EASE_IN_OUT: Self = Self::CubicBezier(0.42, 0.0, 0.58, 1.0);
+
+    /// Eased progress for linear progress `t` in `[0, 1]`. May leave
+    /// `[0, 1]` in between, but is exactly 0 and 1 at the ends.
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        if t == 0.0 || t == 1.0 {
+            return t;
+        }
+        match *self {
+            Self::Linear => t,
+            Self::CubicBezier(x1, y1, x2, y2) => {
+                let s = solve_bezier(x1, x2, t);
+                bezier(y1, y2, s)
+            }
+            Self::Spring => {
+                const DAMPING: f32 = 0.45;
+                const FREQUENCY: f32 = 12.0;
+                let damped = FREQUENCY * (1.0 - DAMPING * DAMPING).sqrt();
+                let decay = (-DAMPING * FREQUENCY * t).exp();
+                1.0 - decay
+                    * ((damped * t).cos() + DAMPING * FREQUENCY / damped * (damped * t).sin())
+            }
+        }
+    }
+}
+
+/// One coordinate of a cubic Bézier from 0 to 1 with control values
+/// `p1` and `p2`, at parameter `s`.
+fn bezier(p1: f32, p2: f32, s: f32) -> f32 {
+    let r = 1.0 - s;
+    3.0 * r * r * s * p1 + 3.0 * r * s * s * p2 + s * s * s
+}
+
+/// The parameter at which the curve's x coordinate reaches `x`. Newton's
+/// method converges in a few steps for sane curves; bisection catches the
+/// rest.
+fn solve_bezier(x1: f32, x2: f32, x: f32) -> f32 {
+    let mut s = x;
+    for _ in 0..8 {
+        let error = bezier(x1, x2, s) - x;
+        if error.abs() < 1e-5 {
+            return s;
+        }
+        let r = 1.0 - s;
+        let slope = 3.0 * r * r * x1 + 6.0 * r * s * (x2 - x1) + 3.0 * s * s * (1.0 - x2);
+        if slope.abs() < 1e-6 {
+            break;
+        }
+        s = (s - error / slope).clamp(0.0, 1.0);
+    }
+
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..32 {
+        s = (low + high) / 2.0;
+        if bezier(x1, x2, s) < x {
+            low = s;
+        } else {
+            high = s;
+        }
+    }
+    s
+}
+
+impl FromStr for Easing {
+    type Err = WallpaperError;
+
+    /// Parses `linear`, `ease`, `ease-in`, `ease-out`, `ease-in-out`,
+    /// `spring` or `cubic-bezier(x1,y1,x2,y2)`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || WallpaperError::InvalidEasing(s.to_string());
+        let s = s.trim().to_ascii_lowercase();
+        match s.as_str() {
+            "linear" => return Ok(Self::Linear),
+            "ease" => return Ok(Self::EASE),
+            "ease-in" => return Ok(Self::EASE_IN),
+            "ease-out" => return Ok(Self::EASE_OUT),
+            "ease-in-out" => return Ok(Self::EASE_IN_OUT),
+            "spring" => return Ok(Self::Spring),
+            _ => {}
+        }
+
+        let args = s
+            .strip_prefix("cubic-bezier(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .ok_or_else(invalid)?;
+        let points = args
+            .split(',')
+            .map(|n| n.trim().parse::<f32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match points[..] {
+            [x1, y1, x2, y2]
+                if (0.0..=1.0).contains(&x1)
+                    && (0.0..=1.0).contains(&x2)
+                    && y1.is_finite()
+                    && y2.is_finite() =>
+            {
+                Ok(Self::CubicBezier(x1, y1, x2, y2))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Easing; 7] = [
+        Easing::Linear,
+        Easing::EASE,
+        Easing::EASE_IN,
+        Easing::EASE_OUT,
+        Easing::EASE_IN_OUT,
+        Easing::CubicBezier(0.1, -0.6, 0.2, 1.8),
+        Easing::Spring,
+    ];
+
+    #[test]
+    fn endpoints_are_exact() {
+        for easing in ALL {
+            assert_eq!(easing.apply(0.0), 0.0, "{easing:?}");
+            assert_eq!(easing.apply(1.0), 1.0, "{easing:?}");
+            assert_eq!(easing.apply(-0.5), 0.0, "{easing:?}");
+            assert_eq!(easing.apply(2.0), 1.0, "{easing:?}");
+        }
+    }
+
+    #[test]
+    fn bezier_with_bounded_y_is_monotonic() {
+        let curves = [
+            Easing::EASE,
+            Easing::EASE_IN,
+            Easing::EASE_OUT,
+            Easing::EASE_IN_OUT,
+            Easing::CubicBezier(0.0, 1.0, 1.0, 0.0),
+            Easing::CubicBezier(1.0, 0.0, 0.0, 1.0),
+            Easing::CubicBezier(0.9, 0.1, 0.1, 0.9),
+        ];
+        for easing in curves {
+            let mut previous = 0.0;
+            for i in 1..=1000 {
+                let value = easing.apply(i as f32 / 1000.0);
+                assert!(value >= previous - 1e-4, "{easing:?} drops at {i}");
+                assert!((0.0..=1.0 + 1e-4).contains(&value), "{easing:?} at {i}");
+                previous = value;
+            }
+        }
+    }
+
+    #[test]
+    fn bezier_matches_its_x_coordinate() {
+        // Control points on the diagonal give the identity.
+        let easing = Easing::CubicBezier(0.3, 0.3, 0.7, 0.7);
+        for i in 0..=100 {
+            let t = i as f32 / 100.0;
+            assert!((easing.apply(t) - t).abs() < 1e-3, "at {t}");
+        }
+    }
+
+    #[test]
+    fn parses_names_and_curves_in_any_case() {
+        let cases = [
+            ("linear", Easing::Linear),
+            (" Ease-In-Out ", Easing::EASE_IN_OUT),
+            ("SPRING", Easing::Spring),
+            (
+                "cubic-bezier(0.1, 0.2, 0.3, 0.4)",
+                Easing::CubicBezier(0.1, 0.2, 0.3, 0.4),
+            ),
+            (
+                "Cubic-Bezier(0,-1,1,2)",
+                Easing::CubicBezier(0.0, -1.0, 1.0, 2.0),
+            ),
+            (
+                "CUBIC-BEZIER(1E-1,0,1,1)",
+                Easing::CubicBezier(0.1, 0.0, 1.0, 1.0),
+            ),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(s.parse::<Easing>().unwrap(), expected, "{s}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_easings() {
+        for s in [
+            "",
+            "bounce",
+            "cubic-bezier",
+            "cubic-bezier()",
+            "cubic-bezier(0.1,0.2,0.3)",
+            "cubic-bezier(0.1,0.2,0.3,0.4,0.5)",
+            "cubic-bezier(0.1,0.2,0.3,0.4",
+            "cubic-bezier(-0.1,0,1,1)",
+            "cubic-bezier(0,0,1.5,1)",
+            "cubic-bezier(0,nan,1,1)",
+            "cubic-bezier(0,0,1,inf)",
+            "cubic-bezier(a,b,c,d)",
+        ] {
+            assert!(s.parse::<Easing>().is_err(), "{s} parsed");
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use crate::core::damage::DamageRect;
+use rayon::prelude::*;
+use wide::{i16x8, u8x16};
+
+/// Width in pixels of the soft edge masks fade across, to avoid jaggies.
+const EDGE: f32 = 1.5;
+
+/// The two frames a transition goes between, as 8-bit four-channel pixels
+/// in the same layout, `width * 4` bytes per row.
+pub struct Frames<'a> {
+    pub old: &'a [u8],
+    pub new: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+}
+
+/// One kind of transition, built for a single output's size. At progress
+/// `0.0` it shows all of `old` and at `1.0` all of `new`.
+pub trait Effect: Send + Sync {
+    fn draw(&self, frames: &Frames, progress: f32, dst: &mut [u8]);
+
+    /// The part of a `width`x`height` output that may differ between
+    /// progress `from` and `to`.
+    fn changed(&self, _from: f32, _to: f32, width: usize, height: usize) -> DamageRect {
+        DamageRect::full(width as i32, height as i32)
+    }
+
+    /// Like `draw`, but only `rects` of `dst` have to be brought up to date.
+    fn draw_rects(&self, frames: &Frames, progress: f32, dst: &mut [u8], rects: &[DamageRect]) {
+        if !rects.is_empty() {
+            self.draw(frames, progress, dst);
+        }
+    }
+}
+
+/// An effect that reveals the new frame through a mask.
+pub trait Mask: Send + Sync {
+    /// Fills `mask` with how much of the new frame shows on row `y`, from
+    /// 0 (old) to 255 (new).
+    fn row(&self, progress: f32, y: usize, mask: &mut [u8]);
+
+    /// As `Effect::changed`.
+    fn changed(&self, _from: f32, _to: f32, width: usize, height: usize) -> DamageRect {
+        DamageRect::full(width as i32, height as i32)
+    }
+}
+
+impl<M: Mask> Effect for M {
+    fn draw(&self, frames: &Frames, progress: f32, dst: &mut [u8]) {
+        let full = DamageRect::full(frames.width as i32, frames.height as i32);
+        self.draw_rects(frames, progress, dst, &[full]);
+    }
+
+    fn changed(&self, from: f32, to: f32, width: usize, height: usize) -> DamageRect {
+        Mask::changed(self, from, to, width, height)
+    }
+
+    fn draw_rects(&self, frames: &Frames, progress: f32, dst: &mut [u8], rects: &[DamageRect]) {
+        let row_len = frames.width * 4;
+        dst.par_chunks_mut(row_len)
+            .zip(
+                frames
+                    .old
+                    .par_chunks(row_len)
+                    .zip(frames.new.par_chunks(row_len)),
+            )
+            .enumerate()
+            .for_each_init(
+                || vec![0; frames.width],
+                |mask, (y, (dst, (old, new)))| {
+                    // The columns of the rects crossing this row.
+                    let Some((left, right)) = rects
+                        .iter()
+                        .filter(|rect| (rect.y..rect.y + rect.height).contains(&(y as i32)))
+                        .map(|rect| (rect.x as usize, (rect.x + rect.width) as usize))
+                        .reduce(|(l1, r1), (l2, r2)| (l1.min(l2), r1.max(r2)))
+                    else {
+                        return;
+                    };
+                    let right = right.min(frames.width);
+                    self.row(progress, y, mask);
+                    for (((dst, old), new), &m) in dst[left * 4..right * 4]
+                        .chunks_exact_mut(4)
+                        .zip(old[left * 4..right * 4].chunks_exact(4))
+                        .zip(new[left * 4..right * 4].chunks_exact(4))
+                        .zip(&mask[left..right])
+                    {
+                        match m {
+                            0 => dst.copy_from_slice(old),
+                            255 => dst.copy_from_slice(new),
+                            m => {
+                                for c in 0..4 {
+                                    let (old, new) = (old[c] as i32, new[c] as i32);
+                                    dst[c] = (old + ((new - old) * m as i32 + 127) / 255) as u8;
+                                }
+                            }
+                        }
+                    }
+                },
+            );
+    }
+}
+
+/// Scales a coverage in `[0, 1]` to a mask value.
+fn coverage(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// A crossfade.
+pub struct Fade;
+
+impl Effect for Fade {
+    fn draw(&self, frames: &Frames, progress: f32, dst: &mut [u8]) {
+        blend(frames.old, frames.new, progress, dst);
+    }
+}
+
+/// A straight edge sweeping across the output in direction `angle`.
+pub struct Wipe {
+    cos: f32,
+    sin: f32,
+    /// Where the edge starts and how far it travels, along the direction.
+    start: f32,
+    span: f32,
+}
+
+impl Wipe {
+    /// `angle` is in degrees clockwise, with 0 sweeping left to right.
+    pub fn new(angle: f32, width: usize, height: usize) -> Self {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let (w, h) = (width as f32, height as f32);
+        let corners = [0.0, w * cos, h * sin, w * cos + h * sin];
+        let start = corners.iter().copied().fold(f32::INFINITY, f32::min);
+        let end = corners.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        Self {
+            cos,
+            sin,
+            start,
+            span: end - start,
+        }
+    }
+}
+
+impl Mask for Wipe {
+    fn row(&self, progress: f32, y: usize, mask: &mut [u8]) {
+        let edge = self.start + progress * (self.span + EDGE);
+        let row = (y as f32 + 0.5) * self.sin;
+        for (x, m) in mask.iter_mut().enumerate() {
+            let along = (x as f32 + 0.5) * self.cos + row;
+            *m = coverage((edge - along) / EDGE);
+        }
+    }
+
+    /// The band the edge swept, for sweeps along an axis. Diagonal bands
+    /// cross most rows, so those redraw everything.
+    fn changed(&self, from: f32, to: f32, width: usize, height: usize) -> DamageRect {
+        let edge = |progress: f32| self.start + progress.clamp(0.0, 1.0) * (self.span + EDGE);
+        let (from, to) = (edge(from), edge(to));
+        let (low, high) = (from.min(to) - EDGE, from.max(to));
+        // Pixels whose centers lie between low and high along `d`.
+        let band = |d: f32| {
+            let (a, b) = (low / d - 0.5, high / d - 0.5);
+            let start = a.min(b).floor() as i32;
+            (start, a.max(b).ceil() as i32 + 1 - start)
+        };
+        let (width, height) = (width as i32, height as i32);
+        if from == to {
+            DamageRect::default()
+        } else if self.sin.abs() < 1e-4 {
+            let (x, w) = band(self.cos);
+            DamageRect::new(x, 0, w, height).clip(width, height)
+        } else if self.cos.abs() < 1e-4 {
+            let (y, h) = band(self.sin);
+            DamageRect::new(0, y, width, h).clip(width, height)
+        } else {
+            DamageRect::full(width, height)
+        }
+    }
+}
+
+/// A circle around a point, growing to reveal the new frame or shrinking
+/// to leave only the old frame's center before it disappears.
+pub struct Circle {
+    center: (f32, f32),
+    radius: f32,
+    grow: bool,
+}
+
+impl Circle {
+    /// `center` is in pixels and may lie outside the output.
+    pub fn new(center: (f32, f32), width: usize, height: usize, grow: bool) -> Self {
+        let (w, h) = (width as f32, height as f32);
+        let radius = [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)]
+            .iter()
+            .map(|&(x, y)| (x - center.0).hypot(y - center.1))
+            .fold(0.0, f32::max);
+        Self {
+            center,
+            radius,
+            grow,
+        }
+    }
+}
+
+impl Mask for Circle {
+    fn row(&self, progress: f32, y: usize, mask: &mut [u8]) {
+        let dy = y as f32 + 0.5 - self.center.1;
+        let travel = self.radius + EDGE;
+        for (x, m) in mask.iter_mut().enumerate() {
+            let distance = (x as f32 + 0.5 - self.center.0).hypot(dy);
+            *m = if self.grow {
+                coverage((progress * travel - distance) / EDGE)
+            } else {
+                coverage((distance - (1.0 - progress) * travel) / EDGE + 1.0)
+            };
+        }
+    }
+
+    /// The square around the larger of the two circles.
+    fn changed(&self, from: f32, to: f32, width: usize, height: usize) -> DamageRect {
+        let (from, to) = (from.clamp(0.0, 1.0), to.clamp(0.0, 1.0));
+        if from == to {
+            return DamageRect::default();
+        }
+        let travel = self.radius + EDGE;
+        let radius = if self.grow {
+            from.max(to) * travel
+        } else {
+            (1.0 - from.min(to)) * travel
+        } + 1.0;
+        let left = (self.center.0 - radius).floor() as i32;
+        let top = (self.center.1 - radius).floor() as i32;
+        let right = (self.center.0 + radius).ceil() as i32;
+        let bottom = (self.center.1 + radius).ceil() as i32;
+        DamageRect::new(left, top, right - left, bottom - top).clip(width as i32, height as i32)
+    }
+}
+
+/// The new frame pushing the old one off the output. Only moves along an
+/// axis, since diagonal pushes would leave uncovered corners.
+pub struct Slide {
+    /// Direction along x or y, each -1, 0 or 1.
+    dx: isize,
+    dy: isize,
+}
+
+impl Slide {
+    /// `angle` is rounded to the nearest multiple of 90 degrees, with 0
+    /// moving left to right.
+    pub fn new(angle: f32) -> Self {
+        match ((angle / 90.0).round() as i64).rem_euclid(4) {
+            0 => Self { dx: 1, dy: 0 },
+            1 => Self { dx: 0, dy: 1 },
+            2 => Self { dx: -1, dy: 0 },
+            _ => Self { dx: 0, dy: -1 },
+        }
+    }
+}
+
+impl Effect for Slide {
+    fn draw(&self, frames: &Frames, progress: f32, dst: &mut [u8]) {
+        let row_len = frames.width * 4;
+        let shift = |len: usize| (progress.clamp(0.0, 1.0) * len as f32).round() as isize;
+
+        if self.dy != 0 {
+            let height = frames.height as isize;
+            let shift = shift(frames.height) * self.dy;
+            dst.par_chunks_mut(row_len)
+                .enumerate()
+                .for_each(|(y, dst)| {
+                    let src = y as isize - shift;
+                    let frame = if (0..height).contains(&src) {
+                        frames.old
+                    } else {
+                        frames.new
+                    };
+                    let start = src.rem_euclid(height) as usize * row_len;
+                    dst.copy_from_slice(&frame[start..start + row_len]);
+                });
+        } else {
+            let shift = (shift(frames.width) * self.dx) * 4;
+            dst.par_chunks_mut(row_len)
+                .zip(
+                    frames
+                        .old
+                        .par_chunks(row_len)
+                        .zip(frames.new.par_chunks(row_len)),
+                )
+                .for_each(|(dst, (old, new))| {
+                    let split = shift.unsigned_abs();
+                    if shift >= 0 {
+                        dst[split..].copy_from_slice(&old[..row_len - split]);
+                        dst[..split].copy_from_slice(&new[row_len - split..]);
+                    } else {
+                        dst[..row_len - split].copy_from_slice(&old[split..]);
+                        dst[row_len - split..].copy_from_slice(&new[..split]);
+                    }
+                });
+        }
+    }
+}
+
+/// Writes `old` mixed with `progress` of `new` into `dst`. All three hold
+/// 8-bit channels in the same layout, so the order doesn't matter.
+pub fn blend(old: &[u8], new: &[u8], progress: f32, dst: &mut [u8]) {
+    const CHUNK: usize = 64 * 1024;
+    // Q15 weight for mul_scale_round_n.
+    let weight = (progress.clamp(0.0, 1.0) * i16::MAX as f32).round() as i16;
+
+    dst.par_chunks_mut(CHUNK)
+        .zip(old.par_chunks(CHUNK).zip(new.par_chunks(CHUNK)))
+        .for_each(|(dst, (old, new))| {
+            let lanes = dst.len() / 16 * 16;
+            for ((dst, old), new) in dst[..lanes]
+                .chunks_exact_mut(16)
+                .zip(old.chunks_exact(16))
+                .zip(new.chunks_exact(16))
+            {
+                let old = u8x16::from(<[u8; 16]>::try_from(old).unwrap());
+                let new = u8x16::from(<[u8; 16]>::try_from(new).unwrap());
+                let mix = |old: i16x8, new: i16x8| old + (new - old).mul_scale_round_n(weight);
+                let low = mix(i16x8::from_u8x16_low(old), i16x8::from_u8x16_low(new));
+                let high = mix(i16x8::from_u8x16_high(old), i16x8::from_u8x16_high(new));
+                dst.copy_from_slice(&u8x16::narrow_i16x8(low, high).to_array());
+            }
+
+            let weight = weight as i32;
+            for ((dst, &old), &new) in dst[lanes..]
+                .iter_mut()
+                .zip(&old[lanes..])
+                .zip(&new[lanes..])
+            {
+                let (old, new) = (old as i32, new as i32);
+                *dst = (old + (((new - old) * weight + (1 << 14)) >> 15)) as u8;
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::damage::PoolDamage;
+
+    const WIDTH: usize = 24;
+    const HEIGHT: usize = 16;
+
+    fn frames() -> (Vec<u8>, Vec<u8>) {
+        let old = (0..WIDTH * HEIGHT * 4).map(|i| (i % 251) as u8).collect();
+        let new = (0..WIDTH * HEIGHT * 4)
+            .map(|i| (i % 7 * 30) as u8)
+            .collect();
+        (old, new)
+    }
+
+    fn effects() -> Vec<Box<dyn Effect>> {
+        vec![
+            Box::new(Fade),
+            Box::new(Wipe::new(0.0, WIDTH, HEIGHT)),
+            Box::new(Wipe::new(90.0, WIDTH, HEIGHT)),
+            Box::new(Wipe::new(180.0, WIDTH, HEIGHT)),
+            Box::new(Wipe::new(270.0, WIDTH, HEIGHT)),
+            Box::new(Wipe::new(30.0, WIDTH, HEIGHT)),
+            Box::new(Circle::new((5.0, 12.0), WIDTH, HEIGHT, true)),
+            Box::new(Circle::new((30.0, -4.0), WIDTH, HEIGHT, false)),
+            Box::new(Slide::new(90.0)),
+        ]
+    }
+
+    const STEPS: [f32; 9] = [0.0, 0.05, 0.1, 0.3, 0.31, 0.6, 0.6, 0.95, 1.0];
+
+    #[test]
+    fn changed_covers_every_differing_pixel() {
+        let (old, new) = frames();
+        let frames = Frames {
+            old: &old,
+            new: &new,
+            width: WIDTH,
+            height: HEIGHT,
+        };
+        for effect in effects() {
+            for pair in STEPS.windows(2) {
+                let mut before = vec![0; old.len()];
+                let mut after = vec![0; old.len()];
+                effect.draw(&frames, pair[0], &mut before);
+                effect.draw(&frames, pair[1], &mut after);
+                let rect = effect.changed(pair[0], pair[1], WIDTH, HEIGHT);
+                for y in 0..HEIGHT {
+                    for x in 0..WIDTH {
+                        let i = (y * WIDTH + x) * 4;
+                        if before[i..i + 4] != after[i..i + 4] {
+                            let pixel = DamageRect::new(x as i32, y as i32, 1, 1);
+                            assert!(rect.contains(&pixel), "{x},{y} outside {rect:?}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_slots_match_full_draws() {
+        let (old, new) = frames();
+        let frames = Frames {
+            old: &old,
+            new: &new,
+            width: WIDTH,
+            height: HEIGHT,
+        };
+        for effect in effects() {
+            let mut damage = PoolDamage::new(2, WIDTH as i32, HEIGHT as i32);
+            let mut slots = [vec![0; old.len()], vec![0; old.len()]];
+            let mut shown = 0.0;
+            for (i, &progress) in STEPS.iter().enumerate() {
+                let slot = i % 2;
+                let changed = [effect.changed(shown, progress, WIDTH, HEIGHT)];
+                let rects = damage.draw(slot, &changed, WIDTH as i32, HEIGHT as i32);
+                effect.draw_rects(&frames, progress, &mut slots[slot], &rects);
+                shown = progress;
+
+                let mut full = vec![0; old.len()];
+                effect.draw(&frames, progress, &mut full);
+                assert!(slots[slot] == full, "slot {slot} stale at {progress}");
+            }
+        }
+    }
+}
@@ -1,4 +1,16 @@
-use crate::WallpaperResult;
+use crate::{
+    core::cache::CacheStats,
+    core::{
+        presentation::FrameStats,
+        slideshow::{Slideshow, SlideshowStatus},
+        transition::Transition,
+    },
+    image::{
+        color::Color, dither::Dither, generate::Generator, ken_burns::KenBurns,
+        render::ScalingMode, video::Video,
+    },
+    WallpaperResult,
+};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use tokio::{
@@ -13,10 +25,47 @@ pub enum IpcMessage {
     SetWallpaper {
         image: PathBuf,
         monitor: Option<String>,
+        mode: ScalingMode,
+        dither: Dither,
+        ken_burns: Option<KenBurns>,
+        transition: Transition,
     },
+    SetColor {
+        color: Color,
+        monitor: Option<String>,
+        transition: Transition,
+    },
+    SetGenerated {
+        generator: Generator,
+        monitor: Option<String>,
+        dither: Dither,
+    },
+    SetVideo {
+        video: Video,
+        monitor: Option<String>,
+    },
+    StartSlideshow(Slideshow),
+    StopSlideshow,
+    SlideshowStatus,
+    CacheStats,
+    CacheClear,
+    FrameStats,
     StopDaemon,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IpcResponse {
+    Ok,
+    CacheStats {
+        buffers: CacheStats,
+        images: CacheStats,
+        disk: Option<CacheStats>,
+    },
+    FrameStats(Vec<(String, FrameStats)>),
+    Slideshow(Option<SlideshowStatus>),
+    Error(String),
+}
+
 pub struct IpcServer {
     listener: UnixListener,
 }
@@ -39,23 +88,38 @@ impl IpcServer {
         }
     }
 
-    pub async fn accept(&self) -> WallpaperResult<(UnixStream, IpcMessage)> {
-        let (mut stream, _) = self.listener.accept().await?;
+    /// Waits for a client. Cancel-safe, unlike `receive`.
+    pub async fn accept(&self) -> WallpaperResult<UnixStream> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(stream)
+    }
+
+    pub async fn receive(stream: &mut UnixStream) -> WallpaperResult<IpcMessage> {
         let mut buf = Vec::new();
         stream.read_to_end(&mut buf).await?;
-        let msg = bincode::deserialize(&buf)?;
-        Ok((stream, msg))
+        Ok(bincode::deserialize(&buf)?)
+    }
+
+    pub async fn respond(stream: &mut UnixStream, response: &IpcResponse) -> WallpaperResult<()> {
+        let data = bincode::serialize(response)?;
+        stream.write_all(&data).await?;
+        stream.shutdown().await?;
+        Ok(())
     }
 }
 
 pub struct IpcClient;
 
 impl IpcClient {
-    pub async fn send_message(msg: &IpcMessage) -> WallpaperResult<()> {
+    pub async fn send_message(msg: &IpcMessage) -> WallpaperResult<IpcResponse> {
         let mut stream = UnixStream::connect(SOCKET_PATH).await?;
         let data = bincode::serialize(msg)?;
         stream.write_all(&data).await?;
-        Ok(())
+        stream.shutdown().await?;
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await?;
+        Ok(bincode::deserialize(&buf)?)
     }
 }
 
@@ -1,11 +1,17 @@
 use crate::{
-    core::buffer::Buffer,
-    utils::{error::WallpaperResult, wayland::WaylandState},
+    core::{
+        buffer::{Buffer, BufferState},
+        damage::{DamageRect, PoolDamage},
+        swizzle::{self, PixelOrder},
+    },
+    utils::{
+        error::{WallpaperError, WallpaperResult},
+        wayland::WaylandState,
+    },
 };
 use log::debug;
 use memmap2::{MmapMut, MmapOptions};
 use std::{
-    arch::x86_64::{__m128i, _mm_set_epi8},
     os::fd::{AsRawFd, BorrowedFd},
     time::Instant,
 };
@@ -14,25 +20,35 @@ use wayland_client::{
     Proxy, QueueHandle,
 };
 
-const BUFFER_COUNT: usize = 2;
-const BUFFER_SIZE: usize = 32 * 1024 * 1024;
+pub(crate) const BUFFER_COUNT: usize = 2;
 
 pub struct BufferPool {
     mmap: MmapMut,
     fd: memfd::Memfd,
+    /// Length of the memfd and mapping; only ever grows.
     size: usize,
     width: i32,
     height: i32,
     current_index: usize,
+    /// Slot holding the most recently written frame.
+    front: Option<usize>,
     buffers: Vec<Buffer>,
+    damage: PoolDamage,
     pool: Option<WlShmPool>,
     stride: i32,
+    format: wl_shm::Format,
 }
 
 impl BufferPool {
-    pub fn new(width: i32, height: i32) -> WallpaperResult<Self> {
-        let stride = width * 4;
-        let size = (height * stride) as usize;
+    /// Maps a pool with room for `BUFFER_COUNT` frames of `height` rows of
+    /// `stride` bytes each.
+    pub fn new(
+        width: i32,
+        height: i32,
+        stride: i32,
+        format: wl_shm::Format,
+    ) -> WallpaperResult<Self> {
+        let size = Self::checked_size(width, height, stride)?;
 
         debug!("Creating buffer pool with size: {}MB", size / 1024 / 1024);
         let fd = memfd::MemfdOptions::new()
@@ -40,8 +56,9 @@ impl BufferPool {
             .close_on_exec(true)
             .create("wallpaper")?;
 
-        fd.as_file().set_len(BUFFER_SIZE as u64)?;
-        let mmap = unsafe { MmapOptions::new().len(BUFFER_SIZE).map_mut(&fd)? };
+        fd.as_file().set_len(size as u64)?;
+        fd.add_seal(memfd::FileSeal::SealShrink)?;
+        let mmap = unsafe { MmapOptions::new().len(size).map_mut(&fd)? };
 
         Ok(Self {
             mmap,
@@ -50,30 +67,208 @@ impl BufferPool {
             width,
             height,
             current_index: 0,
+            front: None,
             buffers: Vec::with_capacity(BUFFER_COUNT),
+            damage: PoolDamage::new(BUFFER_COUNT, width, height),
             pool: None,
             stride,
+            format,
         })
     }
 
-    pub fn write_pixels(&mut self, pixels: &[u8]) {
+    /// The pool size for the given geometry, rejecting geometries that
+    /// `wl_shm` can't describe.
+    fn checked_size(width: i32, height: i32, stride: i32) -> WallpaperResult<usize> {
+        if width <= 0 || height <= 0 || stride < width * 4 {
+            return Err(WallpaperError::Memory(format!(
+                "invalid buffer geometry {width}x{height} with stride {stride}"
+            )));
+        }
+        let size = height as usize * stride as usize * BUFFER_COUNT;
+        if size > i32::MAX as usize {
+            return Err(WallpaperError::Memory(format!(
+                "{width}x{height} buffers exceed the wl_shm pool size limit"
+            )));
+        }
+        Ok(size)
+    }
+
+    fn frame_size(&self) -> usize {
+        self.height as usize * self.stride as usize
+    }
+
+    /// Changes the geometry of the pool's buffers, growing the memfd and the
+    /// `wl_shm_pool` when the new frames don't fit. Existing buffers are
+    /// destroyed since they describe the old geometry.
+    pub fn resize(&mut self, width: i32, height: i32) -> WallpaperResult<()> {
+        self.reconfigure(width, height, width * 4, self.format)
+    }
+
+    /// Like `resize`, also changing the stride and format of the buffers.
+    pub fn reconfigure(
+        &mut self,
+        width: i32,
+        height: i32,
+        stride: i32,
+        format: wl_shm::Format,
+    ) -> WallpaperResult<()> {
+        if (width, height, stride, format) == (self.width, self.height, self.stride, self.format) {
+            return Ok(());
+        }
+
+        let size = Self::checked_size(width, height, stride)?;
+        debug!(
+            "Reconfiguring buffer pool from {}x{} {:?} to {}x{} {:?}",
+            self.width, self.height, self.format, width, height, format
+        );
+
+        if size > self.size {
+            self.fd.as_file().set_len(size as u64)?;
+            self.mmap = unsafe { MmapOptions::new().len(size).map_mut(&self.fd)? };
+            if let Some(pool) = &self.pool {
+                pool.resize(size as i32);
+            }
+            self.size = size;
+        }
+
+        for buffer in self.buffers.drain(..) {
+            buffer.buffer().destroy();
+        }
+        self.width = width;
+        self.height = height;
+        self.stride = stride;
+        self.format = format;
+        self.current_index = 0;
+        self.front = None;
+        self.damage.reset(width, height);
+        Ok(())
+    }
+
+    /// Bytes of shared memory held by the pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn format(&self) -> wl_shm::Format {
+        self.format
+    }
+
+    /// Whether the compositor holds none of the pool's buffers, so its
+    /// memory can be rewritten without tearing.
+    pub fn is_idle(&self) -> bool {
+        self.buffers.iter().all(Buffer::is_released)
+    }
+
+    /// Writes a full frame of `order` pixels into the slot the next
+    /// `get_buffer` call returns, converting to the pool's format if needed.
+    pub fn write_pixels(&mut self, pixels: &[u8], order: PixelOrder) {
         let start = Instant::now();
         debug!("Starting pixel write of {}MB", pixels.len() / 1024 / 1024);
 
-        unsafe {
-            use std::arch::x86_64::{_mm_load_si128, _mm_shuffle_epi8, _mm_store_si128};
-            let src = pixels.as_ptr() as *const __m128i;
-            let dst = self.mmap.as_mut_ptr() as *mut __m128i;
-            let shuffle_mask = _mm_set_epi8(15, 12, 13, 14, 11, 8, 9, 10, 7, 4, 5, 6, 3, 0, 1, 2);
-
-            for i in 0..(pixels.len() / 16) {
-                let pixel_data = _mm_load_si128(src.add(i));
-                let converted = _mm_shuffle_epi8(pixel_data, shuffle_mask);
-                _mm_store_si128(dst.add(i), converted);
+        let frame_size = self.frame_size();
+        let offset = self.current_index * frame_size;
+        self.front = Some(self.current_index);
+        self.damage.written(
+            self.current_index,
+            DamageRect::full(self.width, self.height),
+        );
+        let frame = &mut self.mmap[offset..offset + frame_size];
+        let row_len = self.width as usize * 4;
+        if self.stride as usize == row_len {
+            swizzle::upload(pixels, order, self.format, frame);
+        } else {
+            for (src, dst) in pixels
+                .chunks_exact(row_len)
+                .zip(frame.chunks_exact_mut(self.stride as usize))
+            {
+                swizzle::upload(src, order, self.format, &mut dst[..row_len]);
             }
         }
 
-        debug!("Pixel write completed in {:?}", start.elapsed());
+        debug!(
+            "Pixel write completed in {:?} ({:?} to {:?})",
+            start.elapsed(),
+            order,
+            self.format
+        );
+    }
+
+    /// Lets `fill` write a full frame, already in the pool's format, into the
+    /// slot the next `get_buffer` call returns. The slot only becomes the
+    /// front frame if `fill` returns true.
+    pub fn fill_frame(&mut self, fill: impl FnOnce(&mut [u8]) -> bool) -> bool {
+        let frame_size = self.frame_size();
+        let offset = self.current_index * frame_size;
+        if !fill(&mut self.mmap[offset..offset + frame_size]) {
+            return false;
+        }
+        self.front = Some(self.current_index);
+        self.damage.written(
+            self.current_index,
+            DamageRect::full(self.width, self.height),
+        );
+        true
+    }
+
+    /// The most recently written frame, in the pool's format.
+    pub fn frame(&self) -> Option<&[u8]> {
+        let front = self.front?;
+        let frame_size = self.frame_size();
+        Some(&self.mmap[front * frame_size..(front + 1) * frame_size])
+    }
+
+    /// Like `draw_with`, but `draw` only has to bring the returned rects
+    /// of the slot up to date: the `damage` of this frame plus whatever the
+    /// slot missed while the other one was shown.
+    pub fn draw_damage(
+        &mut self,
+        shm: &wl_shm::WlShm,
+        qh: &QueueHandle<WaylandState>,
+        damage: &[DamageRect],
+        draw: impl FnOnce(&mut [u8], &[DamageRect]),
+    ) -> Option<Buffer> {
+        self.ensure_buffers(shm, qh, BUFFER_COUNT - 1);
+        let slot = (0..BUFFER_COUNT)
+            .map(|i| (self.current_index + i) % BUFFER_COUNT)
+            .find(|&i| self.buffers[i].is_released())?;
+
+        let rects = self.damage.draw(slot, damage, self.width, self.height);
+        let frame_size = self.frame_size();
+        draw(
+            &mut self.mmap[slot * frame_size..(slot + 1) * frame_size],
+            &rects,
+        );
+        debug!("Drew {} damaged rects into slot {}", rects.len(), slot);
+
+        self.front = Some(slot);
+        self.current_index = (slot + 1) % BUFFER_COUNT;
+        self.buffers[slot].set_released(false);
+        Some(self.buffers[slot].clone())
+    }
+
+    /// Lets `draw` write a full frame into a free slot and returns that
+    /// slot's buffer marked busy, alternating slots as the compositor
+    /// releases them. Returns `None` if every slot is busy.
+    pub fn draw_with(
+        &mut self,
+        shm: &wl_shm::WlShm,
+        qh: &QueueHandle<WaylandState>,
+        draw: impl FnOnce(&mut [u8]),
+    ) -> Option<Buffer> {
+        self.ensure_buffers(shm, qh, BUFFER_COUNT - 1);
+        let slot = (0..BUFFER_COUNT)
+            .map(|i| (self.current_index + i) % BUFFER_COUNT)
+            .find(|&i| self.buffers[i].is_released())?;
+
+        let frame_size = self.frame_size();
+        draw(&mut self.mmap[slot * frame_size..(slot + 1) * frame_size]);
+        self.damage
+            .written(slot, DamageRect::full(self.width, self.height));
+
+        self.front = Some(slot);
+        self.current_index = (slot + 1) % BUFFER_COUNT;
+        self.buffers[slot].set_released(false);
+        Some(self.buffers[slot].clone())
     }
 
     pub fn get_buffer(&mut self, shm: &wl_shm::WlShm, qh: &QueueHandle<WaylandState>) -> &Buffer {
@@ -82,17 +277,63 @@ impl BufferPool {
             self.current_index
         );
 
-        if self.buffers.len() < BUFFER_COUNT {
+        let index = self.current_index;
+        self.ensure_buffers(shm, qh, index);
+        self.current_index = (index + 1) % BUFFER_COUNT;
+
+        let buffer = &self.buffers[index];
+        debug!("Returning buffer {:?}", buffer.buffer().id());
+        buffer
+    }
+
+    /// Returns a buffer showing the most recently written frame that the
+    /// compositor isn't holding, marked busy until its next release. When
+    /// the slot the frame was written to is still busy, the frame is copied
+    /// into a free slot instead. Returns `None` if nothing was written yet
+    /// or every slot is busy.
+    pub fn acquire_buffer(
+        &mut self,
+        shm: &wl_shm::WlShm,
+        qh: &QueueHandle<WaylandState>,
+    ) -> Option<Buffer> {
+        let front = self.front?;
+        self.ensure_buffers(shm, qh, BUFFER_COUNT - 1);
+
+        if self.buffers[front].is_released() {
+            self.buffers[front].set_released(false);
+            return Some(self.buffers[front].clone());
+        }
+
+        let free = (0..BUFFER_COUNT).find(|&i| self.buffers[i].is_released())?;
+        debug!(
+            "Buffer {:?} is busy, copying frame to slot {}",
+            self.buffers[front].buffer().id(),
+            free
+        );
+        let frame_size = self.frame_size();
+        self.mmap.copy_within(
+            front * frame_size..(front + 1) * frame_size,
+            free * frame_size,
+        );
+        self.front = Some(free);
+        self.current_index = (free + 1) % BUFFER_COUNT;
+        self.damage.copied(free);
+        self.buffers[free].set_released(false);
+        Some(self.buffers[free].clone())
+    }
+
+    fn ensure_buffers(
+        &mut self,
+        shm: &wl_shm::WlShm,
+        qh: &QueueHandle<WaylandState>,
+        index: usize,
+    ) {
+        while self.buffers.len() <= index {
             debug!("Creating new buffer in pool");
-            let offset = self.current_index * (self.width * self.height * 4) as usize;
+            let offset = self.buffers.len() * self.frame_size();
             let buffer = self.create_buffer(shm, qh, offset);
             self.buffers.push(buffer);
         }
-
-        let buffer = &self.buffers[self.current_index];
-        debug!("Returning buffer {:?}", buffer.buffer().id());
-        self.current_index = (self.current_index + 1) % BUFFER_COUNT;
-        buffer
     }
 
     fn create_buffer(
@@ -119,22 +360,22 @@ impl BufferPool {
             self.width,
             self.height,
             self.stride,
-            wl_shm::Format::Xrgb8888,
+            self.format,
             qh,
-            (),
+            BufferState::new(),
         );
         debug!("Buffer creation took {:?}", start.elapsed());
 
-        Buffer::new(
-            self.width.try_into().unwrap(),
-            self.height.try_into().unwrap(),
-            buffer,
-        )
+        // The geometry was validated as positive when it was set.
+        Buffer::new(self.width as u32, self.height as u32, buffer)
     }
 }
 
 impl Drop for BufferPool {
     fn drop(&mut self) {
+        for buffer in self.buffers.drain(..) {
+            buffer.buffer().destroy();
+        }
         if let Some(pool) = self.pool.take() {
             pool.destroy();
         }
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Identifies the commit a `wp_presentation_feedback` reports on.
+pub struct FeedbackData {
+    /// Key of the layer surface in `WaylandState::layer_surfaces`.
+    pub surface: u32,
+    /// When the committed frame was meant to reach the screen, for frames
+    /// of an animation.
+    pub target: Option<Instant>,
+}
+
+/// Frame counts for one output since the daemon started.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct FrameStats {
+    pub presented: u64,
+    /// Refreshes that animation frames missed their target by.
+    pub dropped: u64,
+    /// Frames replaced before ever reaching the screen.
+    pub discarded: u64,
+    pub refresh: Option<Duration>,
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} presented, {} dropped, {} discarded",
+            self.presented, self.dropped, self.discarded
+        )?;
+        if let Some(refresh) = self.refresh {
+            write!(f, ", {:.2} Hz", 1.0 / refresh.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+/// When a surface's frames reach the screen, from presentation feedback.
+#[derive(Clone, Default)]
+pub struct FrameTiming {
+    last_presented: Option<Instant>,
+    stats: FrameStats,
+}
+
+impl FrameTiming {
+    pub fn presented(&mut self, time: Instant, refresh: Option<Duration>, target: Option<Instant>) {
+        self.stats.presented += 1;
+        if refresh.is_some() {
+            self.stats.refresh = refresh;
+        }
+        self.last_presented = Some(time);
+
+        // Anything within half a refresh of the target made it.
+        if let (Some(target), Some(refresh)) = (target, self.stats.refresh) {
+            let late = time.saturating_duration_since(target).as_secs_f64();
+            self.stats.dropped += (late / refresh.as_secs_f64()).round() as u64;
+        }
+    }
+
+    pub fn discarded(&mut self) {
+        self.stats.discarded += 1;
+    }
+
+    /// The first refresh after `now` on the last reported vblank grid, which
+    /// a frame committed now can make at the earliest.
+    pub fn next_present(&self, now: Instant) -> Option<Instant> {
+        let last = self.last_presented?;
+        let refresh = self.stats.refresh.filter(|r| !r.is_zero())?;
+        let elapsed = now.saturating_duration_since(last);
+        let refreshes = (elapsed.as_nanos() / refresh.as_nanos()) as u32 + 1;
+        Some(last + refresh * refreshes)
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+}
+
+/// Converts a presentation timestamp on `clock` to an `Instant`, by how long
+/// ago it was on that clock.
+pub fn to_instant(clock: u32, secs: u64, nanos: u32) -> Option<Instant> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock as libc::clockid_t, &mut ts) } != 0 {
+        return None;
+    }
+    let now = Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+    let ago = now.saturating_sub(Duration::new(secs, nanos));
+    Instant::now().checked_sub(ago)
+}
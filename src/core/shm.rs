@@ -1,92 +1,129 @@
 use crate::{
-    core::buffer::Buffer,
-    utils::{
-        error::{WallpaperError, WallpaperResult},
-        wayland::WaylandState,
-    },
+    core::pool::{BufferPool, BUFFER_COUNT},
+    utils::error::WallpaperResult,
 };
-use memmap2::{MmapMut, MmapOptions};
-use std::{
-    io::Write,
-    os::{fd::BorrowedFd, unix::io::AsRawFd},
-};
-use wayland_client::{protocol::wl_shm, QueueHandle};
-
-pub struct ShmBuffer {
-    mmap: MmapMut,
-    size: (u32, u32),
-}
+use log::debug;
+use std::cmp::Reverse;
+use wayland_client::protocol::wl_shm;
 
-impl ShmBuffer {
-    pub fn new(width: u32, height: u32) -> WallpaperResult<Self> {
-        let stride = width * 4;
-        let size = stride * height;
+/// Idle pools kept around for reuse; any more are unmapped.
+const MAX_SPARE_POOLS: usize = 4;
 
-        let fd = memfd::MemfdOptions::new()
-            .close_on_exec(true)
-            .create("wallpaper")
-            .map_err(|e| {
-                WallpaperError::IoError(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    e.to_string(),
-                ))
-            })?;
+/// Hands out `BufferPool`s for a given geometry and format, reusing the
+/// memfds and `wl_shm_pool`s of pools that are no longer needed instead of
+/// mapping new ones on every `set`.
+pub struct ShmAllocator {
+    spare: Vec<BufferPool>,
+    /// Bytes idle spare pools may hold.
+    budget: usize,
+}
 
-        fd.as_file()
-            .set_len(size as u64)
-            .map_err(WallpaperError::IoError)?;
+impl ShmAllocator {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            spare: Vec::new(),
+            budget,
+        }
+    }
 
-        let mmap = unsafe {
-            MmapOptions::new()
-                .len(size as usize)
-                .map_mut(&fd)
-                .map_err(WallpaperError::IoError)?
+    /// Returns a pool of `height` rows of `stride` bytes per frame. An idle
+    /// pool that already has room is preferred, then the largest idle pool,
+    /// grown to fit, and only then a new mapping. Pools far larger than
+    /// needed are left alone so tiny buffers don't pin big mappings.
+    pub fn allocate(
+        &mut self,
+        width: i32,
+        height: i32,
+        stride: i32,
+        format: wl_shm::Format,
+    ) -> WallpaperResult<BufferPool> {
+        self.prune();
+        let needed = height as usize * stride as usize;
+        let fits = |pool: &BufferPool| {
+            let frames = pool.size() / needed.max(1);
+            (BUFFER_COUNT..=BUFFER_COUNT * 4).contains(&frames)
         };
+        let index = self
+            .spare
+            .iter()
+            .enumerate()
+            .filter(|(_, pool)| pool.is_idle() && fits(pool))
+            .min_by_key(|(_, pool)| pool.size())
+            .or_else(|| {
+                self.spare
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, pool)| pool.is_idle() && pool.size() < needed * BUFFER_COUNT)
+                    .max_by_key(|(_, pool)| pool.size())
+            })
+            .map(|(i, _)| i);
 
-        Ok(Self {
-            mmap,
-            size: (width, height),
-        })
+        match index {
+            Some(i) => {
+                let mut pool = self.spare.swap_remove(i);
+                debug!(
+                    "Reusing {}MB buffer pool for {}x{}",
+                    pool.size() / 1024 / 1024,
+                    width,
+                    height
+                );
+                pool.reconfigure(width, height, stride, format)?;
+                Ok(pool)
+            }
+            None => BufferPool::new(width, height, stride, format),
+        }
     }
 
-    pub fn as_mut_slice(&mut self) -> &mut [u8] {
-        &mut self.mmap[..]
+    /// Takes back a pool for later `allocate` calls. A pool the compositor
+    /// still reads from is kept but not handed out until it is released,
+    /// since rewriting it would tear and destroying it would blank the
+    /// output.
+    pub fn recycle(&mut self, pool: BufferPool) {
+        self.spare.push(pool);
+        self.prune();
     }
 
-    pub fn create_buffer(&self, shm: &wl_shm::WlShm, qh: &QueueHandle<WaylandState>) -> Buffer {
-        let fd = memfd::MemfdOptions::new()
-            .close_on_exec(true)
-            .create("wallpaper")
-            .expect("Failed to create memfd");
-
-        let size = (self.size.0 * self.size.1 * 4) as u64;
-        fd.as_file()
-            .set_len(size)
-            .expect("Failed to set memfd size");
-
-        fd.as_file()
-            .write_all(&self.mmap)
-            .expect("Failed to write buffer data");
-
-        let pool = shm.create_pool(
-            unsafe { BorrowedFd::borrow_raw(fd.as_raw_fd()) },
-            size as i32,
-            qh,
-            (),
-        );
+    /// Changes how many bytes idle spare pools may hold, unmapping any
+    /// past it.
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.prune();
+    }
 
-        let wl_buffer = pool.create_buffer(
-            0,
-            self.size.0 as i32,
-            self.size.1 as i32,
-            (self.size.0 * 4) as i32,
-            wl_shm::Format::Xrgb8888,
-            qh,
-            (),
-        );
+    /// Unmaps idle spare pools past `MAX_SPARE_POOLS` or the budget,
+    /// keeping the largest. Busy pools are never dropped; they are pruned
+    /// once released.
+    pub fn prune(&mut self) {
+        self.spare
+            .sort_by_key(|pool| (pool.is_idle(), Reverse(pool.size())));
+        let mut kept = 0;
+        let mut bytes = 0;
+        let budget = self.budget;
+        self.spare.retain(|pool| {
+            if !pool.is_idle() {
+                return true;
+            }
+            let keep = kept < MAX_SPARE_POOLS && bytes + pool.size() <= budget;
+            if keep {
+                kept += 1;
+                bytes += pool.size();
+            } else {
+                debug!(
+                    "Unmapping {}MB spare buffer pool",
+                    pool.size() / 1024 / 1024
+                );
+            }
+            keep
+        });
+    }
 
-        pool.destroy();
+    /// Bytes held by spare pools, busy or not.
+    pub fn spare_bytes(&self) -> usize {
+        self.spare.iter().map(BufferPool::size).sum()
+    }
 
-        Buffer::new(self.size.0, self.size.1, wl_buffer)
+    /// Unmaps every spare pool the compositor isn't reading from.
+    pub fn trim(&mut self) {
+        self.spare.retain(|pool| !pool.is_idle());
     }
 }
@@ -0,0 +1,512 @@
+use crate::{
+    core::transition::Transition,
+    image::{dither::Dither, render::ScalingMode},
+    utils::error::{WallpaperError, WallpaperResult},
+};
+use image::ImageReader;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    fmt, fs,
+    hash::{BuildHasher, Hasher},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// One image of a slideshow and how it is shown.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Slide {
+    pub path: PathBuf,
+    /// How long it stays up, if not the slideshow's interval.
+    pub duration: Option<Duration>,
+    pub mode: ScalingMode,
+    pub transition: Transition,
+    /// Names of the outputs it is shown on; empty for all of them.
+    pub outputs: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SlideSource {
+    /// The images in a directory, rescanned once per pass.
+    Dir { path: PathBuf, recursive: bool },
+    /// The entries of a playlist file, as read by the client.
+    Playlist { path: PathBuf, slides: Vec<Slide> },
+}
+
+/// Images shown one after another, looping.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Slideshow {
+    pub source: SlideSource,
+    /// How long each image stays up unless its slide says otherwise.
+    pub interval: Duration,
+    pub shuffle: bool,
+    /// Give every output its own sequence instead of showing the same image
+    /// everywhere.
+    pub independent: bool,
+    /// Scaling and transition of images found in a directory.
+    pub mode: ScalingMode,
+    pub transition: Transition,
+    pub dither: Dither,
+}
+
+impl Slideshow {
+    /// The slides of one pass, in source order.
+    fn slides(&self) -> WallpaperResult<Vec<Slide>> {
+        match &self.source {
+            SlideSource::Dir { path, recursive } => Ok(scan(path, *recursive)?
+                .into_iter()
+                .map(|path| Slide {
+                    path,
+                    duration: None,
+                    mode: self.mode.clone(),
+                    transition: self.transition,
+                    outputs: Vec::new(),
+                })
+                .collect()),
+            SlideSource::Playlist { slides, .. } => Ok(slides.clone()),
+        }
+    }
+
+    fn source_path(&self) -> &Path {
+        match &self.source {
+            SlideSource::Dir { path, .. } | SlideSource::Playlist { path, .. } => path,
+        }
+    }
+}
+
+/// A running slideshow as reported to clients.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SlideshowStatus {
+    /// The directory or playlist played.
+    pub source: PathBuf,
+    pub images: usize,
+    pub interval: Duration,
+    pub shuffle: bool,
+    pub paused: bool,
+    /// Each output's name, the image it shows and the time until the next.
+    pub outputs: Vec<(String, Option<PathBuf>, Duration)>,
+}
+
+impl fmt::Display for SlideshowStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} images every {}",
+            self.source.display(),
+            self.images,
+            humantime::format_duration(self.interval)
+        )?;
+        if self.shuffle {
+            write!(f, ", shuffled")?;
+        }
+        if self.paused {
+            write!(f, ", paused")?;
+        }
+        for (name, image, remaining) in &self.outputs {
+            let remaining = Duration::from_secs(remaining.as_secs());
+            match image {
+                Some(image) => write!(f, "\n{name}: {}", image.display())?,
+                None => write!(f, "\n{name}: nothing yet")?,
+            }
+            write!(f, ", next in {}", humantime::format_duration(remaining))?;
+        }
+        Ok(())
+    }
+}
+
+/// Outputs stepping through the slides together.
+struct Track {
+    outputs: Vec<usize>,
+    order: Vec<Slide>,
+    /// Index into `order` of the next slide.
+    position: usize,
+    /// The pass of `SlideshowState::slides` that `order` was made from.
+    pass: u64,
+    /// The slide last shown on any of `outputs`.
+    current: Option<Slide>,
+    next_change: Instant,
+}
+
+/// The slides of a `Slideshow` and when each output moves on.
+pub struct SlideshowState {
+    slideshow: Slideshow,
+    /// Output names by index, to match slides against.
+    names: Vec<String>,
+    /// The slides as last read, shared by every track.
+    slides: Vec<Slide>,
+    /// Counts reads of the slides, so the first track to finish a pass
+    /// rereads them and the others reuse that.
+    pass: u64,
+    tracks: Vec<Track>,
+    /// The image each output shows, by index, as slides may skip outputs.
+    showing: Vec<Option<PathBuf>>,
+    paused_at: Option<Instant>,
+}
+
+impl SlideshowState {
+    /// Reads the slides and makes every output due for its first one.
+    /// `names` holds the name of each output by index.
+    pub fn new(slideshow: Slideshow, names: Vec<String>, now: Instant) -> WallpaperResult<Self> {
+        let source = slideshow.source_path().display().to_string();
+        if slideshow.interval.is_zero() {
+            return Err(WallpaperError::InvalidSlideshow(
+                "interval must be positive".to_string(),
+            ));
+        }
+        let slides = slideshow.slides()?;
+        if slides.is_empty() {
+            return Err(WallpaperError::InvalidSlideshow(format!(
+                "no images in {source}"
+            )));
+        }
+        info!(
+            "Starting slideshow of {} images from {}",
+            slides.len(),
+            source
+        );
+
+        let groups: Vec<Vec<usize>> = if slideshow.independent {
+            (0..names.len()).map(|i| vec![i]).collect()
+        } else {
+            vec![(0..names.len()).collect()]
+        };
+        let tracks = groups
+            .into_iter()
+            .enumerate()
+            .map(|(i, outputs)| {
+                let mut order = slides.clone();
+                if slideshow.shuffle {
+                    shuffle(&mut order);
+                }
+                Track {
+                    outputs,
+                    // Independent outputs in order start apart.
+                    position: i % order.len(),
+                    order,
+                    pass: 0,
+                    current: None,
+                    next_change: now,
+                }
+            })
+            .collect();
+
+        let showing = vec![None; names.len()];
+        Ok(Self {
+            slideshow,
+            names,
+            slides,
+            pass: 0,
+            tracks,
+            showing,
+            paused_at: None,
+        })
+    }
+
+    pub fn slideshow(&self) -> &Slideshow {
+        &self.slideshow
+    }
+
+    /// Tracks whose slide should change at `now`.
+    pub fn due(&self, now: Instant) -> Vec<usize> {
+        if self.paused_at.is_some() {
+            return Vec::new();
+        }
+        (0..self.tracks.len())
+            .filter(|&i| self.tracks[i].next_change <= now)
+            .collect()
+    }
+
+    /// Moves `track` on to its next slide for any of its outputs, returning
+    /// those outputs and the slide. Passing the end reads the slides again,
+    /// unless another track already did for this pass, and reshuffles.
+    /// `None` if no slide is for these outputs.
+    pub fn advance(&mut self, track: usize) -> Option<(Vec<usize>, Slide)> {
+        let slideshow = &self.slideshow;
+        let names = &self.names;
+        let track = &mut self.tracks[track];
+        // One pass over the slides, plus one to wrap around.
+        for _ in 0..=track.order.len() {
+            if track.position >= track.order.len() {
+                if track.pass == self.pass {
+                    match slideshow.slides() {
+                        Ok(slides) if !slides.is_empty() => self.slides = slides,
+                        Ok(_) => debug!("Slideshow source is empty, keeping the old images"),
+                        Err(e) => debug!("Failed to rescan slideshow source: {}", e),
+                    }
+                    self.pass += 1;
+                }
+                track.order = self.slides.clone();
+                track.pass = self.pass;
+                if slideshow.shuffle {
+                    shuffle(&mut track.order);
+                    // Don't show the last image twice in a row.
+                    let current = track.current.as_ref().map(|slide| &slide.path);
+                    if track.order.len() > 1 && current == track.order.first().map(|s| &s.path) {
+                        let last = track.order.len() - 1;
+                        track.order.swap(0, last);
+                    }
+                }
+                track.position = 0;
+            }
+            let slide = &track.order[track.position];
+            track.position += 1;
+
+            let targets: Vec<usize> = track
+                .outputs
+                .iter()
+                .copied()
+                .filter(|&i| slide.outputs.is_empty() || slide.outputs.contains(&names[i]))
+                .collect();
+            if !targets.is_empty() {
+                for &i in &targets {
+                    self.showing[i] = Some(slide.path.clone());
+                }
+                track.current = Some(slide.clone());
+                return Some((targets, slide.clone()));
+            }
+        }
+        None
+    }
+
+    /// Starts the duration of the slide `track` just switched to.
+    pub fn shown(&mut self, track: usize, now: Instant) {
+        let track = &mut self.tracks[track];
+        let duration = track
+            .current
+            .as_ref()
+            .and_then(|slide| slide.duration)
+            .unwrap_or(self.slideshow.interval);
+        track.next_change = now + duration;
+    }
+
+    /// When the next track changes, unless paused.
+    pub fn next_change(&self) -> Option<Instant> {
+        if self.paused_at.is_some() {
+            return None;
+        }
+        self.tracks.iter().map(|track| track.next_change).min()
+    }
+
+    /// Stops the clock, so paused time doesn't count towards the interval.
+    pub fn pause(&mut self, now: Instant) {
+        self.paused_at.get_or_insert(now);
+    }
+
+    pub fn resume(&mut self, now: Instant) {
+        if let Some(paused_at) = self.paused_at.take() {
+            let paused = now.saturating_duration_since(paused_at);
+            for track in &mut self.tracks {
+                track.next_change += paused;
+            }
+        }
+    }
+
+    pub fn status(&self, now: Instant) -> SlideshowStatus {
+        let now = self.paused_at.unwrap_or(now);
+        let mut outputs: Vec<_> = self
+            .tracks
+            .iter()
+            .flat_map(|track| {
+                track.outputs.iter().map(|&i| {
+                    (
+                        i,
+                        self.showing[i].clone(),
+                        track.next_change.saturating_duration_since(now),
+                    )
+                })
+            })
+            .collect();
+        outputs.sort_by_key(|&(i, ..)| i);
+        SlideshowStatus {
+            source: self.slideshow.source_path().to_path_buf(),
+            images: self.slides.len(),
+            interval: self.slideshow.interval,
+            shuffle: self.slideshow.shuffle,
+            paused: self.paused_at.is_some(),
+            outputs: outputs
+                .into_iter()
+                .map(|(i, image, remaining)| (self.names[i].clone(), image, remaining))
+                .collect(),
+        }
+    }
+}
+
+/// The images in `dir`, sorted by path. Files are recognized by their
+/// contents, so misnamed images are kept and anything else is skipped.
+fn scan(dir: &Path, recursive: bool) -> WallpaperResult<Vec<PathBuf>> {
+    let mut images = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        let entries = match fs::read_dir(&current) {
+            Ok(entries) => entries,
+            // Only the top directory has to be readable.
+            Err(e) if current != dir => {
+                warn!("Skipping {} in slideshow: {}", current.display(), e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Skipping an entry of {}: {}", current.display(), e);
+                    continue;
+                }
+            };
+            let path = entry.path();
+            // Symlinked directories aren't followed, so links can't loop.
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                if recursive {
+                    dirs.push(path);
+                }
+            } else if is_image(&path) {
+                images.push(path);
+            } else {
+                debug!("Skipping {} in slideshow", path.display());
+            }
+        }
+    }
+    images.sort();
+    Ok(images)
+}
+
+/// Whether `path` holds an image in a format the decoder recognizes.
+pub fn is_image(path: &Path) -> bool {
+    ImageReader::open(path)
+        .and_then(ImageReader::with_guessed_format)
+        .is_ok_and(|reader| reader.format().is_some())
+}
+
+/// Fisher-Yates with randomness from the std hasher's keys.
+fn shuffle<T>(items: &mut [T]) {
+    let state = RandomState::new();
+    for i in (1..items.len()).rev() {
+        let mut hasher = state.build_hasher();
+        hasher.write_usize(i);
+        items.swap(i, (hasher.finish() % (i as u64 + 1)) as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    fn write_image(path: &Path) {
+        image::RgbaImage::new(1, 1).save(path).unwrap();
+    }
+
+    #[test]
+    fn scan_skips_symlinked_dirs_and_non_images() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        fs::create_dir(dir.join("sub")).unwrap();
+        write_image(&dir.join("a.png"));
+        write_image(&dir.join("sub/b.png"));
+        fs::write(dir.join("notes.txt"), "not an image").unwrap();
+        symlink(dir, dir.join("sub/loop")).unwrap();
+        symlink(dir.join("a.png"), dir.join("link.png")).unwrap();
+
+        let images = scan(dir, true).unwrap();
+        assert_eq!(
+            images,
+            [
+                dir.join("a.png"),
+                dir.join("link.png"),
+                dir.join("sub/b.png")
+            ]
+        );
+        assert_eq!(scan(dir, false).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn scan_fails_only_for_the_top_directory() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        assert!(scan(&dir.join("nope"), true).is_err());
+    }
+
+    #[test]
+    fn tracks_share_one_read_per_pass() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        write_image(&dir.join("a.png"));
+        write_image(&dir.join("b.png"));
+        let slideshow = Slideshow {
+            source: SlideSource::Dir {
+                path: dir.to_path_buf(),
+                recursive: false,
+            },
+            interval: Duration::from_secs(60),
+            shuffle: false,
+            independent: true,
+            mode: ScalingMode::default(),
+            transition: Transition::default(),
+            dither: Dither::default(),
+        };
+        let names = vec!["A".to_string(), "B".to_string()];
+        let mut state = SlideshowState::new(slideshow, names, Instant::now()).unwrap();
+        let path = |state: &mut SlideshowState, track| state.advance(track).unwrap().1.path;
+
+        // Track 1 starts on the second image, so it finishes the pass first.
+        assert_eq!(path(&mut state, 1), dir.join("b.png"));
+        assert_eq!(path(&mut state, 1), dir.join("a.png"));
+        write_image(&dir.join("c.png"));
+
+        // Track 0 reuses what track 1 read instead of reading again.
+        assert_eq!(path(&mut state, 0), dir.join("a.png"));
+        assert_eq!(path(&mut state, 0), dir.join("b.png"));
+        assert_eq!(path(&mut state, 0), dir.join("a.png"));
+        assert_eq!(path(&mut state, 0), dir.join("b.png"));
+        assert_eq!(state.status(Instant::now()).images, 2);
+
+        // The next pass picks up the new image.
+        assert_eq!(path(&mut state, 1), dir.join("b.png"));
+        assert_eq!(path(&mut state, 1), dir.join("a.png"));
+        assert_eq!(state.status(Instant::now()).images, 3);
+    }
+
+    #[test]
+    fn status_follows_slides_per_output() {
+        let slide = |path: &str, outputs: &[&str]| Slide {
+            path: PathBuf::from(path),
+            duration: None,
+            mode: ScalingMode::default(),
+            transition: Transition::default(),
+            outputs: outputs.iter().map(|name| name.to_string()).collect(),
+        };
+        let slideshow = Slideshow {
+            source: SlideSource::Playlist {
+                path: PathBuf::from("/playlist"),
+                slides: vec![slide("/a.png", &["B"]), slide("/b.png", &[])],
+            },
+            interval: Duration::from_secs(60),
+            shuffle: false,
+            independent: false,
+            mode: ScalingMode::default(),
+            transition: Transition::default(),
+            dither: Dither::default(),
+        };
+        let names = vec!["A".to_string(), "B".to_string()];
+        let mut state = SlideshowState::new(slideshow, names, Instant::now()).unwrap();
+        let images = |state: &SlideshowState| -> Vec<Option<PathBuf>> {
+            let status = state.status(Instant::now());
+            status
+                .outputs
+                .into_iter()
+                .map(|(_, image, _)| image)
+                .collect()
+        };
+        let shown = |image: &str| Some(PathBuf::from(image));
+
+        assert_eq!(state.advance(0).unwrap().0, [1]);
+        assert_eq!(images(&state), [None, shown("/a.png")]);
+        assert_eq!(state.advance(0).unwrap().0, [0, 1]);
+        assert_eq!(images(&state), [shown("/b.png"), shown("/b.png")]);
+        assert_eq!(state.advance(0).unwrap().0, [1]);
+        assert_eq!(images(&state), [shown("/b.png"), shown("/a.png")]);
+    }
+}
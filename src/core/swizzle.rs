@@ -0,0 +1,246 @@
+//! RGBA → XRGB8888 conversion.
+//!
+//! `wl_shm` XRGB8888 is little-endian, so each pixel sits in memory as
+//! B, G, R, X. Converting from RGBA swaps the first and third byte of every
+//! pixel; the fourth byte is carried over and ignored by the compositor.
+//! The same swap converts BGRA back to RGBA.
+
+use once_cell::sync::Lazy;
+use wayland_client::protocol::wl_shm;
+
+/// Byte order of one XRGB8888 pixel expressed as indices into RGBA.
+const SHUFFLE: [u8; 16] = [2, 1, 0, 3, 6, 5, 4, 7, 10, 9, 8, 11, 14, 13, 12, 15];
+
+static KERNEL: Lazy<Kernel> = Lazy::new(Kernel::detect);
+
+/// Byte order of decoded pixels in memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PixelOrder {
+    Rgba,
+    Bgra,
+}
+
+impl PixelOrder {
+    /// The order whose bytes match `format` in memory, so uploads are a copy.
+    pub fn native(format: wl_shm::Format) -> Self {
+        match format {
+            wl_shm::Format::Xbgr8888 | wl_shm::Format::Abgr8888 => Self::Rgba,
+            _ => Self::Bgra,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Ssse3,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Kernel {
+    /// The fastest kernel the running CPU supports.
+    pub fn detect() -> Self {
+        Self::available().pop().unwrap_or(Self::Scalar)
+    }
+
+    /// Every kernel the running CPU supports, slowest first.
+    pub fn available() -> Vec<Self> {
+        #[allow(unused_mut)]
+        let mut kernels = vec![Self::Scalar];
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("ssse3") {
+                kernels.push(Self::Ssse3);
+            }
+            if is_x86_feature_detected!("avx2") {
+                kernels.push(Self::Avx2);
+            }
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                kernels.push(Self::Neon);
+            }
+        }
+
+        kernels
+    }
+
+    /// Converts `src` into `dst`. Both are truncated to the shorter length,
+    /// rounded down to whole pixels.
+    pub fn run(self, src: &[u8], dst: &mut [u8]) {
+        let len = src.len().min(dst.len()) & !3;
+        let (src, dst) = (&src[..len], &mut dst[..len]);
+
+        let done = match self {
+            Self::Scalar => 0,
+            // SAFETY: kernels are only constructed after their CPU feature
+            // was detected at runtime.
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::Ssse3 => unsafe { x86::ssse3(src, dst) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::Avx2 => unsafe { x86::avx2(src, dst) },
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => unsafe { neon::neon(src, dst) },
+        };
+
+        scalar(&src[done..], &mut dst[done..]);
+    }
+}
+
+/// Converts RGBA pixels in `src` to XRGB8888 in `dst` using the fastest
+/// kernel available.
+pub fn rgba_to_xrgb(src: &[u8], dst: &mut [u8]) {
+    KERNEL.run(src, dst);
+}
+
+/// Writes `src`, laid out as `order`, into `dst` laid out as `format`.
+/// Falls back to a plain copy when the two already agree.
+pub fn upload(src: &[u8], order: PixelOrder, format: wl_shm::Format, dst: &mut [u8]) {
+    if order == PixelOrder::native(format) {
+        let len = src.len().min(dst.len());
+        dst[..len].copy_from_slice(&src[..len]);
+    } else {
+        rgba_to_xrgb(src, dst);
+    }
+}
+
+pub fn kernel() -> Kernel {
+    *KERNEL
+}
+
+fn scalar(src: &[u8], dst: &mut [u8]) {
+    for (s, d) in src.chunks_exact(4).zip(dst.chunks_exact_mut(4)) {
+        d.copy_from_slice(&[s[2], s[1], s[0], s[3]]);
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    use super::SHUFFLE;
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    /// Returns the number of bytes converted, always a multiple of 16.
+    #[target_feature(enable = "ssse3")]
+    pub(super) unsafe fn ssse3(src: &[u8], dst: &mut [u8]) -> usize {
+        let mask = _mm_loadu_si128(SHUFFLE.as_ptr() as *const __m128i);
+        let chunks = src.len() / 16;
+
+        for i in 0..chunks {
+            let pixels = _mm_loadu_si128(src.as_ptr().add(i * 16) as *const __m128i);
+            let converted = _mm_shuffle_epi8(pixels, mask);
+            _mm_storeu_si128(dst.as_mut_ptr().add(i * 16) as *mut __m128i, converted);
+        }
+
+        chunks * 16
+    }
+
+    /// Returns the number of bytes converted, always a multiple of 32.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn avx2(src: &[u8], dst: &mut [u8]) -> usize {
+        let lane = _mm_loadu_si128(SHUFFLE.as_ptr() as *const __m128i);
+        let mask = _mm256_broadcastsi128_si256(lane);
+        let chunks = src.len() / 32;
+
+        for i in 0..chunks {
+            let pixels = _mm256_loadu_si256(src.as_ptr().add(i * 32) as *const __m256i);
+            let converted = _mm256_shuffle_epi8(pixels, mask);
+            _mm256_storeu_si256(dst.as_mut_ptr().add(i * 32) as *mut __m256i, converted);
+        }
+
+        chunks * 32
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use super::SHUFFLE;
+    use std::arch::aarch64::*;
+
+    /// Returns the number of bytes converted, always a multiple of 16.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn neon(src: &[u8], dst: &mut [u8]) -> usize {
+        let mask = vld1q_u8(SHUFFLE.as_ptr());
+        let chunks = src.len() / 16;
+
+        for i in 0..chunks {
+            let pixels = vld1q_u8(src.as_ptr().add(i * 16));
+            vst1q_u8(dst.as_mut_ptr().add(i * 16), vqtbl1q_u8(pixels, mask));
+        }
+
+        chunks * 16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that differ at every position, so a wrong shuffle shows up.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    fn reference(src: &[u8]) -> Vec<u8> {
+        let mut dst = vec![0; src.len() & !3];
+        scalar(src, &mut dst);
+        dst
+    }
+
+    #[test]
+    fn kernels_match_scalar() {
+        for kernel in Kernel::available() {
+            // Whole vectors of every kernel plus every tail of 0-15 pixels.
+            for pixels in (0..16).chain((16..80).step_by(16).flat_map(|n| n..n + 16)) {
+                // Odd byte lengths leave a partial pixel that must be ignored.
+                for extra in [0, 1, 3] {
+                    // Offsets so that neither slice starts aligned.
+                    for offset in 0..4 {
+                        let len = pixels * 4 + extra;
+                        let backing = pattern(len + offset);
+                        let src = &backing[offset..];
+                        let mut out = vec![0xaa; len + offset];
+                        kernel.run(src, &mut out[offset..]);
+
+                        let expected = reference(src);
+                        assert_eq!(
+                            &out[offset..offset + expected.len()],
+                            &expected[..],
+                            "{kernel:?} with {pixels} pixels, {extra} extra bytes, offset {offset}"
+                        );
+                        // Nothing past the last whole pixel is written.
+                        assert!(out[offset + expected.len()..].iter().all(|&b| b == 0xaa));
+                        assert!(out[..offset].iter().all(|&b| b == 0xaa));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scalar_swaps_red_and_blue() {
+        assert_eq!(
+            reference(&[1, 2, 3, 4, 5, 6, 7, 8]),
+            [3, 2, 1, 4, 7, 6, 5, 8]
+        );
+    }
+
+    #[test]
+    fn upload_copies_native_order() {
+        let src = pattern(64);
+        let mut dst = vec![0; 64];
+        upload(&src, PixelOrder::Bgra, wl_shm::Format::Xrgb8888, &mut dst);
+        assert_eq!(dst, src);
+        upload(&src, PixelOrder::Rgba, wl_shm::Format::Xrgb8888, &mut dst);
+        assert_eq!(dst, reference(&src));
+    }
+}
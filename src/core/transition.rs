@@ -0,0 +1,377 @@
+use crate::{
+    core::{
+        buffer::Buffer,
+        easing::Easing,
+        effect::{Circle, Effect, Fade, Frames, Slide, Wipe},
+        pool::BufferPool,
+    },
+    display::monitor::Monitor,
+    utils::wayland::WaylandState,
+};
+use clap::ValueEnum;
+use log::{debug, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use wayland_client::QueueHandle;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum TransitionKind {
+    /// Switch to the new wallpaper at once
+    #[default]
+    None,
+    /// Crossfade from the previous wallpaper
+    Fade,
+    /// Sweep a straight edge across in the direction of --angle
+    Wipe,
+    /// Push the previous wallpaper out along the axis nearest --angle
+    Slide,
+    /// Grow a circle from --position, or the center of each output
+    Grow,
+    /// Shrink the previous wallpaper into a circle around --position, or
+    /// the center of each output
+    Outer,
+    /// Pick any of the above, with a random angle
+    Random,
+}
+
+/// How a new wallpaper replaces the one on screen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Transition {
+    pub kind: TransitionKind,
+    pub duration: Duration,
+    /// Direction of wipes and slides, in degrees clockwise from rightwards.
+    pub angle: f32,
+    /// Center of circles in the compositor's logical space.
+    pub position: Option<(i32, i32)>,
+    pub easing: Easing,
+    /// Upper bound on frames drawn per second, on top of each output's
+    /// refresh rate.
+    pub max_fps: Option<u32>,
+}
+
+impl Transition {
+    pub fn is_animated(&self) -> bool {
+        self.kind != TransitionKind::None && !self.duration.is_zero()
+    }
+
+    /// Replaces `Random` with a concrete kind and angle, so that every
+    /// output runs the same transition.
+    pub fn resolve(self) -> Self {
+        if self.kind != TransitionKind::Random {
+            return self;
+        }
+        const KINDS: [TransitionKind; 5] = [
+            TransitionKind::Fade,
+            TransitionKind::Wipe,
+            TransitionKind::Slide,
+            TransitionKind::Grow,
+            TransitionKind::Outer,
+        ];
+        let seed = RandomState::new().build_hasher().finish();
+        let kind = KINDS[(seed % KINDS.len() as u64) as usize];
+        let angle = ((seed >> 32) % 360) as f32;
+        debug!("Random transition picked {:?} at {}°", kind, angle);
+        Self {
+            kind,
+            angle,
+            ..self
+        }
+    }
+
+    /// Whether outputs showing the same frame still need their own
+    /// transition frames.
+    pub fn is_positional(&self) -> bool {
+        self.position.is_some() && matches!(self.kind, TransitionKind::Grow | TransitionKind::Outer)
+    }
+
+    /// Builds the effect for one output. `Random` should be resolved first.
+    pub fn effect(&self, monitor: &Monitor) -> Box<dyn Effect> {
+        let (width, height) = (monitor.width as usize, monitor.height as usize);
+        match self.kind {
+            TransitionKind::None | TransitionKind::Fade | TransitionKind::Random => Box::new(Fade),
+            TransitionKind::Wipe => Box::new(Wipe::new(self.angle, width, height)),
+            TransitionKind::Slide => Box::new(Slide::new(self.angle)),
+            TransitionKind::Grow => {
+                Box::new(Circle::new(self.center(monitor), width, height, true))
+            }
+            TransitionKind::Outer => {
+                Box::new(Circle::new(self.center(monitor), width, height, false))
+            }
+        }
+    }
+
+    /// Shortest time between frames on `monitor`: its refresh interval, or
+    /// longer if `max_fps` asks for fewer frames.
+    fn frame_interval(&self, monitor: &Monitor) -> Duration {
+        let refresh = monitor.refresh_interval().unwrap_or(Duration::ZERO);
+        let cap = self
+            .max_fps
+            .filter(|&fps| fps > 0)
+            .map_or(Duration::ZERO, |fps| Duration::from_secs(1) / fps);
+        refresh.max(cap)
+    }
+
+    /// `position` in `monitor`'s pixels, or its center without one.
+    fn center(&self, monitor: &Monitor) -> (f32, f32) {
+        let (width, height) = (monitor.width as f32, monitor.height as f32);
+        let Some((x, y)) = self.position else {
+            return (width / 2.0, height / 2.0);
+        };
+        let (left, top) = monitor.logical_position();
+        let (logical_width, logical_height) = monitor.logical_size();
+        (
+            (x - left) as f32 * width / logical_width.max(1) as f32,
+            (y - top) as f32 * height / logical_height.max(1) as f32,
+        )
+    }
+}
+
+/// What a set of surfaces shows once a transition ends.
+pub enum Target {
+    /// A rendered wallpaper, attached through its pool.
+    Pool(Arc<Mutex<BufferPool>>),
+    /// A solid color given as a full frame to blend towards, and the buffer
+    /// shown on each surface afterwards.
+    Color {
+        frame: Vec<u8>,
+        buffers: Vec<Buffer>,
+    },
+}
+
+/// One output group's part of a transition: the surfaces showing it, the
+/// frame they showed before, and a pool the in-between frames are drawn to.
+pub struct OutputTransition {
+    surface_ids: Vec<u32>,
+    old: Vec<u8>,
+    target: Target,
+    pool: BufferPool,
+    effect: Box<dyn Effect>,
+    width: usize,
+    height: usize,
+    interval: Duration,
+    last_frame: Option<Instant>,
+    /// Progress of the frame the surfaces show.
+    shown: f32,
+}
+
+impl OutputTransition {
+    /// `old` must be a full frame of `monitor` laid out like `pool`'s
+    /// buffers.
+    pub fn new(
+        surface_ids: Vec<u32>,
+        monitor: &Monitor,
+        transition: &Transition,
+        old: Vec<u8>,
+        target: Target,
+        pool: BufferPool,
+    ) -> Self {
+        Self {
+            surface_ids,
+            old,
+            target,
+            pool,
+            effect: transition.effect(monitor),
+            width: monitor.width as usize,
+            height: monitor.height as usize,
+            interval: transition.frame_interval(monitor),
+            last_frame: None,
+            shown: 0.0,
+        }
+    }
+
+    /// When the next frame may be drawn, regardless of frame callbacks.
+    fn next_frame(&self) -> Option<Instant> {
+        // Callbacks jitter around the refresh interval, so allow a frame a
+        // little early rather than skipping every other one.
+        self.last_frame
+            .map(|last| last + self.interval - self.interval / 4)
+    }
+
+    fn is_ready(&self, now: Instant, state: &mut WaylandState) -> bool {
+        if self.next_frame().is_some_and(|next| now < next) {
+            return false;
+        }
+        self.surface_ids.iter().all(|&id| {
+            state
+                .get_layer_surface(id)
+                .is_none_or(|surface| surface.is_draw_ready())
+        })
+    }
+
+    /// When a frame committed `now` should reach the screen, from the
+    /// presentation feedback of the first surface that has some.
+    fn present_time(&self, now: Instant, state: &mut WaylandState) -> Instant {
+        self.surface_ids
+            .iter()
+            .find_map(|&id| state.get_layer_surface(id)?.next_present(now))
+            .unwrap_or(now)
+    }
+
+    fn draw(
+        &mut self,
+        now: Instant,
+        target: Instant,
+        progress: f32,
+        state: &mut WaylandState,
+        qh: &QueueHandle<WaylandState>,
+    ) {
+        let locked;
+        let new = match &self.target {
+            Target::Pool(pool) => {
+                locked = pool.lock();
+                locked.frame().unwrap_or(&[])
+            }
+            Target::Color { frame, .. } => frame,
+        };
+        if new.len() != self.old.len() {
+            return;
+        }
+
+        let frames = Frames {
+            old: &self.old,
+            new,
+            width: self.width,
+            height: self.height,
+        };
+        let effect = &self.effect;
+        let damage = [effect.changed(self.shown, progress, self.width, self.height)];
+        let Some(buffer) = self
+            .pool
+            .draw_damage(state.get_shm(), qh, &damage, |dst, rects| {
+                effect.draw_rects(&frames, progress, dst, rects)
+            })
+        else {
+            debug!("No free buffer for transition frame, skipping");
+            return;
+        };
+        self.last_frame = Some(now);
+        self.shown = progress;
+        for &id in &self.surface_ids {
+            if let Some(surface) = state.get_layer_surface(id) {
+                surface.expect_present(target);
+                surface.attach_damaged(&buffer, Some(&damage), qh);
+            }
+        }
+    }
+
+    /// Attaches the target and hands back the pool in-between frames were
+    /// drawn to. `acquired` holds the buffers already taken from target
+    /// pools, since outputs sharing a pool must share its buffer too.
+    fn finish(
+        self,
+        state: &mut WaylandState,
+        qh: &QueueHandle<WaylandState>,
+        acquired: &mut Vec<(Arc<Mutex<BufferPool>>, Buffer)>,
+    ) -> BufferPool {
+        match self.target {
+            Target::Pool(pool) => {
+                let buffer = match acquired.iter().find(|(p, _)| Arc::ptr_eq(p, &pool)) {
+                    Some((_, buffer)) => buffer.clone(),
+                    None => {
+                        let Some(buffer) = pool.lock().acquire_buffer(state.get_shm(), qh) else {
+                            warn!("Final transition buffer is busy");
+                            return self.pool;
+                        };
+                        acquired.push((Arc::clone(&pool), buffer.clone()));
+                        buffer
+                    }
+                };
+                for &id in &self.surface_ids {
+                    if let Some(surface) = state.get_layer_surface(id) {
+                        surface.attach_buffer(&buffer, qh);
+                    }
+                }
+            }
+            Target::Color { buffers, .. } => {
+                for (&id, buffer) in self.surface_ids.iter().zip(&buffers) {
+                    if let Some(surface) = state.get_layer_surface(id) {
+                        buffer.set_released(false);
+                        surface.attach_buffer(buffer, qh);
+                    }
+                }
+            }
+        }
+        self.pool
+    }
+}
+
+/// A transition in progress on every output at once.
+pub struct ActiveTransition {
+    start: Instant,
+    duration: Duration,
+    easing: Easing,
+    outputs: Vec<OutputTransition>,
+}
+
+impl ActiveTransition {
+    pub fn new(transition: &Transition, outputs: Vec<OutputTransition>) -> Self {
+        Self {
+            start: Instant::now(),
+            duration: transition.duration,
+            easing: transition.easing,
+            outputs,
+        }
+    }
+
+    /// Adds outputs that started transitioning along with these.
+    pub fn extend(&mut self, outputs: Vec<OutputTransition>) {
+        self.outputs.extend(outputs);
+    }
+
+    /// When the last frame is due; the daemon wakes up by then even if no
+    /// frame callback arrives, e.g. because an output is off.
+    pub fn end(&self) -> Instant {
+        self.start + self.duration
+    }
+
+    /// The earliest of the end and the next frame any output may draw
+    /// after `now`, for outputs held back by their frame interval rather
+    /// than a frame callback.
+    pub fn next_wakeup(&self, now: Instant) -> Instant {
+        self.outputs
+            .iter()
+            .filter_map(OutputTransition::next_frame)
+            .filter(|&next| next > now)
+            .fold(self.end(), Instant::min)
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        now >= self.end()
+    }
+
+    /// Draws the next frame on every output whose previous frame the
+    /// compositor has shown and whose frame interval has passed. Each frame
+    /// shows the progress at the time it is expected on screen, so outputs
+    /// with different refresh rates end together.
+    pub fn step(&mut self, now: Instant, state: &mut WaylandState, qh: &QueueHandle<WaylandState>) {
+        for output in &mut self.outputs {
+            if output.is_ready(now, state) {
+                let target = output.present_time(now, state);
+                let linear = (target.saturating_duration_since(self.start).as_secs_f32()
+                    / self.duration.as_secs_f32())
+                .min(1.0);
+                output.draw(now, target, self.easing.apply(linear), state, qh);
+            }
+        }
+    }
+
+    /// Shows every target and returns the pools used for in-between frames.
+    pub fn finish(
+        self,
+        state: &mut WaylandState,
+        qh: &QueueHandle<WaylandState>,
+    ) -> Vec<BufferPool> {
+        debug!("Transition finished after {:?}", self.start.elapsed());
+        let mut acquired = Vec::new();
+        self.outputs
+            .into_iter()
+            .map(|output| output.finish(state, qh, &mut acquired))
+            .collect()
+    }
+}
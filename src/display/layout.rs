@@ -0,0 +1,277 @@
+use crate::{display::monitor::Monitor, utils::error::WallpaperError};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Pixel density assumed for outputs that don't report a physical size.
+const FALLBACK_PIXELS_PER_MM: f64 = 96.0 / 25.4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BezelSize {
+    Millimeters(f64),
+    Pixels(u32),
+}
+
+/// Width of the frame around an output, added as an invisible gutter on
+/// every side of it when spanning. Applies to all outputs when `output` is
+/// `None`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bezel {
+    pub output: Option<String>,
+    pub size: BezelSize,
+}
+
+impl FromStr for BezelSize {
+    type Err = WallpaperError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || WallpaperError::InvalidScaling(format!("invalid bezel size: {s}"));
+        if let Some(mm) = s.strip_suffix("mm") {
+            let mm: f64 = mm.trim().parse().map_err(|_| invalid())?;
+            if !mm.is_finite() || mm < 0.0 {
+                return Err(invalid());
+            }
+            Ok(Self::Millimeters(mm))
+        } else {
+            let px = s.strip_suffix("px").unwrap_or(s);
+            px.trim().parse().map(Self::Pixels).map_err(|_| invalid())
+        }
+    }
+}
+
+impl FromStr for Bezel {
+    type Err = WallpaperError;
+
+    /// Parses `[OUTPUT:]SIZE`, e.g. `DP-1:12mm`, `40px` or `8mm`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once(':') {
+            Some((output, size)) => Ok(Self {
+                output: Some(output.to_string()),
+                size: size.parse()?,
+            }),
+            None => Ok(Self {
+                output: None,
+                size: s.parse()?,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Rect {
+    pub fn right(&self) -> f64 {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> f64 {
+        self.y + self.height
+    }
+}
+
+/// The virtual canvas a spanned wallpaper is cut from, in logical
+/// coordinates, with bezel gutters inserted between outputs.
+pub struct SpanLayout {
+    pub canvas: Rect,
+    pub outputs: Vec<Rect>,
+}
+
+impl SpanLayout {
+    pub fn new(monitors: &[Monitor], bezels: &[Bezel]) -> Self {
+        let rects: Vec<Rect> = monitors
+            .iter()
+            .map(|m| {
+                let (x, y) = m.logical_position();
+                let (width, height) = m.logical_size();
+                Rect {
+                    x: x as f64,
+                    y: y as f64,
+                    width: width as f64,
+                    height: height as f64,
+                }
+            })
+            .collect();
+        let gutters: Vec<(f64, f64)> = monitors.iter().map(|m| bezel_for(m, bezels)).collect();
+
+        let shift_x = gutter_shifts(&rects, |r| (r.x, r.right()), |i| gutters[i].0);
+        let shift_y = gutter_shifts(&rects, |r| (r.y, r.bottom()), |i| gutters[i].1);
+
+        let outputs: Vec<Rect> = rects
+            .iter()
+            .enumerate()
+            .map(|(i, r)| Rect {
+                x: r.x + shift_x[i],
+                y: r.y + shift_y[i],
+                ..*r
+            })
+            .collect();
+
+        let canvas = outputs
+            .iter()
+            .copied()
+            .reduce(|a, b| {
+                let x = a.x.min(b.x);
+                let y = a.y.min(b.y);
+                Rect {
+                    x,
+                    y,
+                    width: a.right().max(b.right()) - x,
+                    height: a.bottom().max(b.bottom()) - y,
+                }
+            })
+            .unwrap_or_default();
+
+        Self { canvas, outputs }
+    }
+
+    /// Region of a `width`x`height` image that lands on output `index` when
+    /// the image covers the whole canvas, centered.
+    pub fn source_rect(&self, index: usize, width: u32, height: u32) -> Rect {
+        let output = self.outputs[index];
+        let scale = (self.canvas.width / width as f64).max(self.canvas.height / height as f64);
+        let offset_x = (width as f64 * scale - self.canvas.width) / 2.0;
+        let offset_y = (height as f64 * scale - self.canvas.height) / 2.0;
+
+        Rect {
+            x: (output.x - self.canvas.x + offset_x) / scale,
+            y: (output.y - self.canvas.y + offset_y) / scale,
+            width: output.width / scale,
+            height: output.height / scale,
+        }
+    }
+}
+
+/// Logical size of the bezel around `monitor` along each axis.
+fn bezel_for(monitor: &Monitor, bezels: &[Bezel]) -> (f64, f64) {
+    let bezel = bezels
+        .iter()
+        .find(|b| b.output.is_some() && b.output == monitor.output_name)
+        .or_else(|| bezels.iter().find(|b| b.output.is_none()));
+
+    match bezel.map(|b| b.size) {
+        None => (0.0, 0.0),
+        Some(BezelSize::Pixels(px)) => {
+            let (width, height) = monitor.logical_size();
+            (
+                px as f64 * width as f64 / monitor.width.max(1) as f64,
+                px as f64 * height as f64 / monitor.height.max(1) as f64,
+            )
+        }
+        Some(BezelSize::Millimeters(mm)) => {
+            let (x_density, y_density) = monitor.logical_pixels_per_mm().unwrap_or_else(|| {
+                warn!(
+                    "Output {} has no physical size, assuming 96 DPI for bezels",
+                    monitor.name
+                );
+                (FALLBACK_PIXELS_PER_MM, FALLBACK_PIXELS_PER_MM)
+            });
+            (mm * x_density, mm * y_density)
+        }
+    }
+}
+
+/// How far each output moves along one axis so that every output before it
+/// leaves room for both bezels in between.
+fn gutter_shifts(
+    rects: &[Rect],
+    span: impl Fn(&Rect) -> (f64, f64),
+    gutter: impl Fn(usize) -> f64,
+) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..rects.len()).collect();
+    order.sort_by(|&a, &b| span(&rects[a]).0.total_cmp(&span(&rects[b]).0));
+
+    let mut shifts = vec![0.0; rects.len()];
+    for (n, &i) in order.iter().enumerate() {
+        let (start, _) = span(&rects[i]);
+        shifts[i] = order[..n]
+            .iter()
+            .filter(|&&j| span(&rects[j]).1 <= start)
+            .map(|&j| shifts[j] + gutter(j) + gutter(i))
+            .fold(0.0, f64::max);
+    }
+    shifts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use wayland_client::{protocol::wl_output::WlOutput, Connection, Proxy};
+
+    #[test]
+    fn parses_bezels() {
+        let cases = [
+            ("DP-1:12mm", Some("DP-1"), BezelSize::Millimeters(12.0)),
+            ("40px", None, BezelSize::Pixels(40)),
+            ("8mm", None, BezelSize::Millimeters(8.0)),
+            ("HDMI-A-1: 3 px", Some("HDMI-A-1"), BezelSize::Pixels(3)),
+        ];
+        for (s, output, size) in cases {
+            let bezel: Bezel = s.parse().unwrap();
+            assert_eq!(bezel.output.as_deref(), output, "{s}");
+            assert_eq!(bezel.size, size, "{s}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_bezels() {
+        for s in ["-1mm", "NaNmm", "infmm", "abc", "-1px", "DP-1:", ""] {
+            assert!(s.parse::<Bezel>().is_err(), "{s}");
+        }
+    }
+
+    /// A 1920x1080 output at `x`, 4 logical pixels per millimeter wide.
+    fn monitor(connection: &Connection, name: &str, x: i32) -> Monitor {
+        let output = WlOutput::inert(connection.backend().downgrade());
+        let mut monitor = Monitor::new(output, 0);
+        monitor.output_name = Some(name.to_string());
+        (monitor.logical_x, monitor.logical_y) = (x, 0);
+        (monitor.logical_width, monitor.logical_height) = (1920, 1080);
+        (monitor.width, monitor.height) = (1920, 1080);
+        (monitor.width_mm, monitor.height_mm) = (480, 270);
+        monitor
+    }
+
+    #[test]
+    fn inserts_gutters_between_side_by_side_outputs() {
+        let (socket, _server) = UnixStream::pair().unwrap();
+        let connection = Connection::from_socket(socket).unwrap();
+        let monitors = [
+            monitor(&connection, "DP-1", 0),
+            monitor(&connection, "DP-2", 1920),
+        ];
+        let bezels = ["DP-1:5mm".parse().unwrap(), "2px".parse().unwrap()];
+        let layout = SpanLayout::new(&monitors, &bezels);
+
+        // 20px of DP-1's bezel plus 2px of DP-2's.
+        let rect = |x, width| Rect {
+            x,
+            y: 0.0,
+            width,
+            height: 1080.0,
+        };
+        assert_eq!(layout.outputs, [rect(0.0, 1920.0), rect(1942.0, 1920.0)]);
+        assert_eq!(layout.canvas, rect(0.0, 3862.0));
+
+        // An image twice the canvas size loses twice the gutter.
+        let doubled = |x, width| Rect {
+            x,
+            y: 0.0,
+            width,
+            height: 2160.0,
+        };
+        assert_eq!(layout.source_rect(0, 7724, 2160), doubled(0.0, 3840.0));
+        assert_eq!(layout.source_rect(1, 7724, 2160), doubled(3884.0, 3840.0));
+
+        // A taller image is cropped evenly at the top and bottom.
+        let source = layout.source_rect(1, 3862, 2160);
+        assert_eq!((source.x, source.y), (1942.0, 540.0));
+        assert_eq!((source.width, source.height), (1920.0, 1080.0));
+    }
+}
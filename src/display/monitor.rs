@@ -1,28 +1,52 @@
-use wayland_client::protocol::wl_output::WlOutput;
+use std::time::Duration;
+use wayland_client::protocol::wl_output::{Transform, WlOutput};
+use wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::ZxdgOutputV1;
 
 #[derive(Debug, Clone)]
 pub struct Monitor {
     pub output: WlOutput,
+    pub xdg_output: Option<ZxdgOutputV1>,
     pub name: u32,
+    pub output_name: Option<String>,
+    pub description: Option<String>,
     pub x: i32,
     pub y: i32,
     pub width: i32,
     pub height: i32,
+    pub logical_x: i32,
+    pub logical_y: i32,
+    pub logical_width: i32,
+    pub logical_height: i32,
+    pub width_mm: i32,
+    pub height_mm: i32,
     pub refresh: i32,
     pub scale: f64,
+    /// Rotation of the content relative to the panel, which `width_mm`
+    /// and `height_mm` describe unrotated.
+    pub transform: Transform,
 }
 
 impl Monitor {
     pub fn new(output: WlOutput, name: u32) -> Self {
         Self {
             output,
+            xdg_output: None,
             name,
+            output_name: None,
+            description: None,
             x: 0,
             y: 0,
             width: 0,
             height: 0,
+            logical_x: 0,
+            logical_y: 0,
+            logical_width: 0,
+            logical_height: 0,
+            width_mm: 0,
+            height_mm: 0,
             refresh: 0,
             scale: 1.0,
+            transform: Transform::Normal,
         }
     }
 }
@@ -70,21 +94,88 @@ impl MonitorBuilder {
     pub fn build(self) -> Monitor {
         Monitor {
             output: self.output,
+            xdg_output: None,
             name: self.name,
+            output_name: None,
+            description: None,
             x: self.x,
             y: self.y,
             width: self.width,
             height: self.height,
+            logical_x: 0,
+            logical_y: 0,
+            logical_width: 0,
+            logical_height: 0,
+            width_mm: 0,
+            height_mm: 0,
             refresh: self.refresh,
             scale: 1.0,
+            transform: Transform::Normal,
         }
     }
 }
 impl Monitor {
-    pub fn physical_size(&self) -> (u32, u32) {
-        (
-            (self.width as f64 * self.scale) as u32,
-            (self.height as f64 * self.scale) as u32,
-        )
+    /// The connector name, e.g. `DP-1`, or the global name without one.
+    pub fn display_name(&self) -> String {
+        self.output_name
+            .clone()
+            .unwrap_or_else(|| format!("output {}", self.name))
+    }
+
+    /// Time between refreshes of the current mode, if the compositor
+    /// reported its rate.
+    pub fn refresh_interval(&self) -> Option<Duration> {
+        // wl_output reports the rate in mHz.
+        (self.refresh > 0).then(|| Duration::from_secs_f64(1000.0 / self.refresh as f64))
+    }
+
+    /// Position in the compositor's logical space, as reported by xdg-output.
+    ///
+    /// Falls back to the `wl_output` geometry when xdg-output is unavailable.
+    pub fn logical_position(&self) -> (i32, i32) {
+        if self.has_logical_geometry() {
+            (self.logical_x, self.logical_y)
+        } else {
+            (self.x, self.y)
+        }
+    }
+
+    /// Size in the compositor's logical space, as reported by xdg-output.
+    ///
+    /// Falls back to the mode size divided by the output scale.
+    pub fn logical_size(&self) -> (i32, i32) {
+        if self.has_logical_geometry() {
+            (self.logical_width, self.logical_height)
+        } else {
+            (
+                (self.width as f64 / self.scale).round() as i32,
+                (self.height as f64 / self.scale).round() as i32,
+            )
+        }
+    }
+
+    pub fn has_logical_geometry(&self) -> bool {
+        self.logical_width > 0 && self.logical_height > 0
+    }
+
+    /// Logical pixels per millimeter, derived from the physical size the
+    /// output reports. `None` for outputs without a physical size (projectors,
+    /// virtual outputs).
+    pub fn logical_pixels_per_mm(&self) -> Option<(f64, f64)> {
+        if self.width_mm <= 0 || self.height_mm <= 0 {
+            return None;
+        }
+        let (width, height) = self.logical_size();
+        // The logical size is rotated, the physical one is not.
+        let (width_mm, height_mm) = match self.transform {
+            Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270 => {
+                (self.height_mm, self.width_mm)
+            }
+            _ => (self.width_mm, self.height_mm),
+        };
+        Some((
+            width as f64 / width_mm as f64,
+            height as f64 / height_mm as f64,
+        ))
     }
 }
@@ -0,0 +1,66 @@
+use crate::utils::error::WallpaperError;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// An opaque sRGB color.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub fn rgba(&self) -> [u8; 4] {
+        [self.r, self.g, self.b, 0xff]
+    }
+}
+
+impl FromStr for Color {
+    type Err = WallpaperError;
+
+    /// Parses `#rgb` or `#rrggbb`, with the `#` optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || WallpaperError::InvalidColor(s.to_string());
+        let hex = s.trim().trim_start_matches('#');
+        // from_str_radix would also take a leading sign.
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        let channel = |digits: &str| u8::from_str_radix(digits, 16).map_err(|_| invalid());
+        match hex.len() {
+            3 => Ok(Self {
+                r: channel(&hex[0..1])? * 0x11,
+                g: channel(&hex[1..2])? * 0x11,
+                b: channel(&hex[2..3])? * 0x11,
+            }),
+            6 => Ok(Self {
+                r: channel(&hex[0..2])?,
+                g: channel(&hex[2..4])?,
+                b: channel(&hex[4..6])?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_short_and_long_forms() {
+        let color = Color::from_str("#1e1e2e").unwrap();
+        assert_eq!(color.rgba(), [0x1e, 0x1e, 0x2e, 0xff]);
+        let color = Color::from_str("f0a").unwrap();
+        assert_eq!(color.rgba(), [0xff, 0x00, 0xaa, 0xff]);
+    }
+
+    #[test]
+    fn rejects_signs_and_non_hex() {
+        for s in ["+12345", "#-1234a", "+ab", "12345g", "#12", "é12"] {
+            assert!(Color::from_str(s).is_err(), "{s} parsed");
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use clap::ValueEnum;
+use image::{DynamicImage, RgbaImage};
+use once_cell::sync::Lazy;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// How a high-precision frame is reduced to 8 bits per channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize, ValueEnum)]
+pub enum DitherMethod {
+    /// Dither frames with more than 8 bits per channel, such as 16-bit or
+    /// float images and generated backgrounds
+    #[default]
+    Auto,
+    /// Round to the nearest 8-bit value without dithering
+    Off,
+    /// 8x8 ordered Bayer matrix
+    Bayer,
+    /// 64x64 blue-noise threshold map
+    BlueNoise,
+}
+
+/// The space the dither thresholds are applied in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize, ValueEnum)]
+pub enum DitherSpace {
+    /// Dither the sRGB-encoded values
+    #[default]
+    Srgb,
+    /// Pick between neighbouring codes so the average is right in linear light
+    Linear,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Dither {
+    pub method: DitherMethod,
+    pub space: DitherSpace,
+}
+
+impl Dither {
+    /// Converts `img` to 8-bit RGBA, dithering if it holds more precision
+    /// than that and dithering is enabled. Thresholds are indexed by output
+    /// pixel, so `img` should already be at output resolution.
+    pub fn quantize(&self, img: &DynamicImage) -> RgbaImage {
+        let map = match self.method {
+            DitherMethod::Off => return img.to_rgba8(),
+            DitherMethod::Auto | DitherMethod::BlueNoise => &*BLUE_NOISE,
+            DitherMethod::Bayer => &*BAYER,
+        };
+
+        match img {
+            DynamicImage::ImageRgba16(buf) => {
+                self.dither(buf.width(), buf.height(), buf.as_raw(), map, |s| {
+                    s as f32 / 65535.0
+                })
+            }
+            DynamicImage::ImageRgba32F(buf) => {
+                self.dither(buf.width(), buf.height(), buf.as_raw(), map, |s| s)
+            }
+            img if has_high_precision(img) => {
+                let buf = img.to_rgba32f();
+                self.dither(buf.width(), buf.height(), buf.as_raw(), map, |s| s)
+            }
+            img => img.to_rgba8(),
+        }
+    }
+
+    fn dither<S: Copy + Sync>(
+        &self,
+        width: u32,
+        height: u32,
+        src: &[S],
+        map: &ThresholdMap,
+        value: impl Fn(S) -> f32 + Sync,
+    ) -> RgbaImage {
+        let row_len = width as usize * 4;
+        let mut out = RgbaImage::new(width, height);
+        out.par_chunks_mut(row_len)
+            .zip(src.par_chunks(row_len))
+            .enumerate()
+            .for_each(|(y, (dst, src))| {
+                for (x, (dst, src)) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)).enumerate()
+                {
+                    let threshold = map.get(x, y);
+                    for c in 0..3 {
+                        dst[c] = match self.space {
+                            DitherSpace::Srgb => quantize_srgb(value(src[c]), threshold),
+                            DitherSpace::Linear => quantize_linear(value(src[c]), threshold),
+                        };
+                    }
+                    dst[3] = (value(src[3]).clamp(0.0, 1.0) * 255.0).round() as u8;
+                }
+            });
+        out
+    }
+}
+
+fn has_high_precision(img: &DynamicImage) -> bool {
+    let color = img.color();
+    color.bits_per_pixel() / color.channel_count() as u16 > 8
+}
+
+fn quantize_srgb(value: f32, threshold: f32) -> u8 {
+    (value * 255.0 + threshold).floor().clamp(0.0, 255.0) as u8
+}
+
+/// Rounds up with the probability that makes the mix of the two nearest
+/// codes match `value` in linear light rather than in sRGB.
+fn quantize_linear(value: f32, threshold: f32) -> u8 {
+    let scaled = value.clamp(0.0, 1.0) * 255.0;
+    let low = scaled.floor().min(254.0);
+    let (low_linear, high_linear) = (
+        srgb_to_linear(low / 255.0),
+        srgb_to_linear((low + 1.0) / 255.0),
+    );
+    let fraction =
+        (srgb_to_linear(value.clamp(0.0, 1.0)) - low_linear) / (high_linear - low_linear);
+    low as u8 + (fraction > threshold) as u8
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// A tileable square of thresholds in `[0, 1)`.
+struct ThresholdMap {
+    size: usize,
+    thresholds: Vec<f32>,
+}
+
+impl ThresholdMap {
+    fn from_ranks(size: usize, ranks: &[usize]) -> Self {
+        let count = ranks.len() as f32;
+        Self {
+            size,
+            thresholds: ranks.iter().map(|&r| (r as f32 + 0.5) / count).collect(),
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> f32 {
+        self.thresholds[(y % self.size) * self.size + x % self.size]
+    }
+}
+
+static BAYER: Lazy<ThresholdMap> = Lazy::new(|| {
+    const SIZE: usize = 8;
+    let ranks: Vec<usize> = (0..SIZE * SIZE)
+        .map(|i| {
+            let (x, y) = (i % SIZE, i / SIZE);
+            // Interleave the bits of x ^ y and y, most significant last.
+            let mut rank = 0;
+            for bit in 0..3 {
+                rank |= (((x ^ y) >> bit) & 1) << (5 - 2 * bit);
+                rank |= ((y >> bit) & 1) << (4 - 2 * bit);
+            }
+            rank
+        })
+        .collect();
+    ThresholdMap::from_ranks(SIZE, &ranks)
+});
+
+static BLUE_NOISE: Lazy<ThresholdMap> = Lazy::new(|| {
+    const SIZE: usize = 64;
+    ThresholdMap::from_ranks(SIZE, &void_and_cluster(SIZE))
+});
+
+/// Ulichney's void-and-cluster method: ranks every cell of a toroidal
+/// `size`x`size` grid so that any prefix of the ranking is evenly spread.
+fn void_and_cluster(size: usize) -> Vec<usize> {
+    let count = size * size;
+    const SIGMA: f32 = 1.5;
+
+    // Gaussian falloff for every toroidal offset.
+    let kernel: Vec<f32> = (0..count)
+        .map(|i| {
+            let wrap = |d: usize| d.min(size - d) as f32;
+            let (dx, dy) = (wrap(i % size), wrap(i / size));
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+
+    struct Pattern<'a> {
+        size: usize,
+        kernel: &'a [f32],
+        set: Vec<bool>,
+        energy: Vec<f32>,
+    }
+
+    impl Pattern<'_> {
+        fn toggle(&mut self, cell: usize, on: bool) {
+            self.set[cell] = on;
+            let sign = if on { 1.0 } else { -1.0 };
+            let (cx, cy) = (cell % self.size, cell / self.size);
+            for (i, energy) in self.energy.iter_mut().enumerate() {
+                let dx = (i % self.size + self.size - cx) % self.size;
+                let dy = (i / self.size + self.size - cy) % self.size;
+                *energy += sign * self.kernel[dy * self.size + dx];
+            }
+        }
+
+        /// The set cell with the most set neighbours.
+        fn tightest_cluster(&self) -> usize {
+            (0..self.set.len())
+                .filter(|&i| self.set[i])
+                .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+                .unwrap()
+        }
+
+        /// The unset cell furthest from any set one.
+        fn largest_void(&self) -> usize {
+            (0..self.set.len())
+                .filter(|&i| !self.set[i])
+                .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+                .unwrap()
+        }
+    }
+
+    let mut pattern = Pattern {
+        size,
+        kernel: &kernel,
+        set: vec![false; count],
+        energy: vec![0.0; count],
+    };
+
+    // A fixed-seed xorshift keeps the map identical between runs.
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let initial = count / 10;
+    while pattern.set.iter().filter(|&&s| s).count() < initial {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let cell = (seed % count as u64) as usize;
+        if !pattern.set[cell] {
+            pattern.toggle(cell, true);
+        }
+    }
+
+    // Move points from clusters into voids until the pattern is stable.
+    for _ in 0..count {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster, false);
+        let void = pattern.largest_void();
+        pattern.toggle(void, true);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; count];
+    let prototype = (pattern.set.clone(), pattern.energy.clone());
+
+    // Rank the initial points by removing the tightest clusters first.
+    for rank in (0..initial).rev() {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster, false);
+        ranks[cluster] = rank;
+    }
+
+    // Then fill the largest voids from the initial pattern onwards.
+    (pattern.set, pattern.energy) = prototype;
+    for rank in initial..count {
+        let void = pattern.largest_void();
+        pattern.toggle(void, true);
+        ranks[void] = rank;
+    }
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgba};
+
+    /// The rank of every cell, recovered from its threshold.
+    fn ranks(map: &ThresholdMap) -> Vec<usize> {
+        let count = map.thresholds.len() as f32;
+        map.thresholds
+            .iter()
+            .map(|t| (t * count - 0.5).round() as usize)
+            .collect()
+    }
+
+    fn assert_permutation(mut ranks: Vec<usize>, count: usize) {
+        assert_eq!(ranks.len(), count);
+        ranks.sort_unstable();
+        assert!(ranks.into_iter().eq(0..count));
+    }
+
+    #[test]
+    fn maps_rank_every_cell_once() {
+        assert_permutation(ranks(&BAYER), 64);
+        assert_permutation(void_and_cluster(64), 64 * 64);
+    }
+
+    /// A flat 64x64 16-bit image, one tile of either map.
+    fn flat(value: u16) -> DynamicImage {
+        DynamicImage::ImageRgba16(ImageBuffer::from_pixel(64, 64, Rgba([value; 4])))
+    }
+
+    fn mean(img: &RgbaImage, to_linear: bool) -> f64 {
+        let sum: f64 = img
+            .pixels()
+            .map(|p| {
+                let code = p[0] as f32 / 255.0;
+                (if to_linear {
+                    srgb_to_linear(code)
+                } else {
+                    code
+                }) as f64
+            })
+            .sum();
+        sum / (img.width() * img.height()) as f64
+    }
+
+    #[test]
+    fn dithered_tiles_average_to_the_input() {
+        let img = flat(32768);
+        let input = 32768.0 / 65535.0;
+        for method in [
+            DitherMethod::Auto,
+            DitherMethod::Bayer,
+            DitherMethod::BlueNoise,
+        ] {
+            let srgb = Dither {
+                method,
+                space: DitherSpace::Srgb,
+            }
+            .quantize(&img);
+            assert!(
+                (mean(&srgb, false) - input).abs() < 0.5 / 255.0 / 64.0 + 1e-6,
+                "{method:?}: {}",
+                mean(&srgb, false)
+            );
+            // Both neighbouring codes are used, not just the nearest.
+            assert!(srgb.pixels().any(|p| p[0] == 127) && srgb.pixels().any(|p| p[0] == 128));
+
+            let linear = Dither {
+                method,
+                space: DitherSpace::Linear,
+            }
+            .quantize(&img);
+            let step = srgb_to_linear(128.0 / 255.0) - srgb_to_linear(127.0 / 255.0);
+            let expected = srgb_to_linear(input as f32) as f64;
+            assert!(
+                (mean(&linear, true) - expected).abs() < step as f64 / 64.0,
+                "{method:?}: {}",
+                mean(&linear, true)
+            );
+        }
+    }
+
+    #[test]
+    fn auto_leaves_8_bit_images_alone() {
+        let img = ImageBuffer::from_fn(16, 16, |x, y| {
+            Rgba([(x * 16) as u8, (y * 16) as u8, (x ^ y) as u8, 200])
+        });
+        let quantized = Dither::default().quantize(&DynamicImage::ImageRgba8(img.clone()));
+        assert_eq!(quantized, img);
+    }
+
+    #[test]
+    fn off_does_not_dither() {
+        let img = flat(32768);
+        let quantized = Dither {
+            method: DitherMethod::Off,
+            space: DitherSpace::Srgb,
+        }
+        .quantize(&img);
+        assert_eq!(quantized, img.to_rgba8());
+        assert!(quantized.pixels().all(|p| *p == Rgba([128; 4])));
+    }
+}
@@ -0,0 +1,294 @@
+use crate::{
+    core::{animation::Scene, swizzle},
+    display::monitor::Monitor,
+    image::{color::Color, dither::Dither},
+};
+use clap::ValueEnum;
+use image::{DynamicImage, Rgba32FImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{f32::consts::TAU, time::Duration};
+use wayland_client::protocol::wl_shm;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum GeneratorKind {
+    /// Bands of the palette drifting across the output
+    Gradient,
+    /// Interfering sine waves
+    Plasma,
+    /// Smooth noise flowing along a warped field
+    Flow,
+    /// Stars flying towards the viewer
+    Starfield,
+}
+
+/// An animated background computed from scratch every frame.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Generator {
+    pub kind: GeneratorKind,
+    pub seed: u64,
+    /// Colors the background is made of; empty picks a default.
+    pub palette: Vec<Color>,
+    /// Multiplier on how fast the background moves.
+    pub speed: f32,
+    /// Fraction of the output resolution rendered at, upscaled by the
+    /// compositor.
+    pub scale: f32,
+    pub fps: u32,
+}
+
+const DEFAULT_PALETTE: [Color; 4] = [
+    Color {
+        r: 0x1e,
+        g: 0x1e,
+        b: 0x2e,
+    },
+    Color {
+        r: 0x89,
+        g: 0xb4,
+        b: 0xfa,
+    },
+    Color {
+        r: 0xcb,
+        g: 0xa6,
+        b: 0xf7,
+    },
+    Color {
+        r: 0xf5,
+        g: 0xc2,
+        b: 0xe7,
+    },
+];
+
+const STAR_COUNT: usize = 600;
+
+/// Draws a `Generator` at reduced resolution in float precision, so the
+/// dither smooths out its gradients, then uploads it in the shm format.
+pub struct GeneratorScene {
+    generator: Generator,
+    dither: Dither,
+    format: wl_shm::Format,
+    sizes: Vec<(usize, usize)>,
+    /// The palette as float sRGB.
+    palette: Vec<[f32; 3]>,
+    noise: Noise,
+    /// Random per-seed phases and frequencies.
+    params: [f32; 8],
+    /// Star positions in `[0, 1)`, the last coordinate being depth.
+    stars: Vec<[f32; 3]>,
+}
+
+impl GeneratorScene {
+    pub fn new(
+        generator: Generator,
+        dither: Dither,
+        format: wl_shm::Format,
+        monitors: &[Monitor],
+    ) -> Self {
+        let scale = generator.scale.clamp(0.05, 1.0);
+        let sizes = monitors
+            .iter()
+            .map(|m| {
+                (
+                    ((m.width as f32 * scale).round() as usize).max(1),
+                    ((m.height as f32 * scale).round() as usize).max(1),
+                )
+            })
+            .collect();
+        let palette = if generator.palette.is_empty() {
+            &DEFAULT_PALETTE[..]
+        } else {
+            &generator.palette[..]
+        }
+        .iter()
+        .map(|c| [c.r, c.g, c.b].map(|v| v as f32 / 255.0))
+        .collect();
+
+        let mut rng = Rng(generator.seed);
+        let params = std::array::from_fn(|_| rng.next_f32());
+        let stars = (0..STAR_COUNT)
+            .map(|_| [rng.next_f32(), rng.next_f32(), rng.next_f32()])
+            .collect();
+
+        Self {
+            noise: Noise::new(generator.seed),
+            generator,
+            dither,
+            format,
+            sizes,
+            palette,
+            params,
+            stars,
+        }
+    }
+
+    /// The palette as a loop, `t` wrapping around at 1.
+    fn palette_at(&self, t: f32) -> [f32; 3] {
+        let count = self.palette.len();
+        let position = t.rem_euclid(1.0) * count as f32;
+        let i = position as usize % count;
+        let (a, b) = (self.palette[i], self.palette[(i + 1) % count]);
+        let f = smoothstep(position.fract());
+        std::array::from_fn(|c| a[c] + (b[c] - a[c]) * f)
+    }
+
+    /// Color at `(x, y)`, both in output heights from the top left, `t`
+    /// seconds in at normal speed.
+    fn shade(&self, x: f32, y: f32, aspect: f32, t: f32) -> [f32; 3] {
+        let p = &self.params;
+        match self.generator.kind {
+            GeneratorKind::Gradient => {
+                let angle = p[0] * TAU + t * 0.05;
+                let (sin, cos) = angle.sin_cos();
+                let along = (x - aspect / 2.0) * cos + (y - 0.5) * sin;
+                self.palette_at(along * 0.5 + t * 0.03)
+            }
+            GeneratorKind::Plasma => {
+                let t = t * 0.6;
+                let (cx, cy) = (
+                    aspect / 2.0 + (t * 0.7 + p[1] * TAU).sin() * 0.4,
+                    0.5 + (t * 0.5 + p[2] * TAU).cos() * 0.4,
+                );
+                let v = (x * (3.0 + p[3] * 4.0) + t).sin()
+                    + (y * (3.0 + p[4] * 4.0) - t * 1.3).sin()
+                    + ((x + y) * (2.0 + p[5] * 3.0) + t * 0.7).sin()
+                    + (((x - cx).hypot(y - cy)) * (6.0 + p[6] * 4.0) - t).sin();
+                self.palette_at(v * 0.125 + 0.5)
+            }
+            GeneratorKind::Flow => {
+                let t = t * 0.08;
+                let (x, y) = (x * 2.5, y * 2.5);
+                let warp_x = self.noise.get(x + t, y - t * 0.5);
+                let warp_y = self.noise.get(x + 5.2 - t * 0.7, y + 1.3 + t);
+                let v = self.noise.get(x + 2.0 * warp_x, y + 2.0 * warp_y + t);
+                self.palette_at(v * 0.6 + 0.5 + t * 0.2)
+            }
+            GeneratorKind::Starfield => self.palette[0],
+        }
+    }
+
+    /// Adds the stars on top of `img`, centered on it.
+    fn draw_stars(&self, img: &mut Rgba32FImage, t: f32) {
+        let (width, height) = (img.width() as f32, img.height() as f32);
+        let star = self.palette.last().copied().unwrap_or([1.0; 3]);
+        for &[x, y, z] in &self.stars {
+            // Fly towards the viewer, wrapping back to the far plane.
+            let depth = (z - t * 0.05).rem_euclid(1.0) + 0.02;
+            let (px, py) = (
+                (x - 0.5) / depth * 0.5 * height + width / 2.0,
+                (y - 0.5) / depth * 0.5 * height + height / 2.0,
+            );
+            if !(0.0..width).contains(&px) || !(0.0..height).contains(&py) {
+                continue;
+            }
+            // Near stars are brighter, far ones fade in from nothing.
+            let brightness = ((1.0 - depth).max(0.0).powi(2) * 1.5).min(1.0);
+            let pixel = img.get_pixel_mut(px as u32, py as u32);
+            for c in 0..3 {
+                pixel[c] = (pixel[c] + star[c] * brightness).min(1.0);
+            }
+        }
+    }
+}
+
+impl Scene for GeneratorScene {
+    fn draw(&self, index: usize, elapsed: Duration, dst: &mut [u8]) {
+        let (width, height) = self.sizes[index];
+        let t = elapsed.as_secs_f32() * self.generator.speed;
+        let aspect = width as f32 / height as f32;
+
+        let mut img = Rgba32FImage::new(width as u32, height as u32);
+        img.par_chunks_mut(width * 4)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let v = (y as f32 + 0.5) / height as f32;
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let u = (x as f32 + 0.5) / height as f32;
+                    let [r, g, b] = self.shade(u, v, aspect, t);
+                    pixel.copy_from_slice(&[r, g, b, 1.0]);
+                }
+            });
+        if self.generator.kind == GeneratorKind::Starfield {
+            self.draw_stars(&mut img, t);
+        }
+
+        let frame = self.dither.quantize(&DynamicImage::ImageRgba32F(img));
+        swizzle::upload(frame.as_raw(), swizzle::PixelOrder::Rgba, self.format, dst);
+    }
+
+    fn frame_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.generator.fps.max(1)
+    }
+
+    fn size(&self, index: usize) -> (usize, usize) {
+        self.sizes[index]
+    }
+}
+
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// SplitMix64, enough to spread a seed into parameters.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn next_f32(&mut self) -> f32 {
+        (self.next() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// Seeded 2D gradient noise, roughly in `[-1, 1]`.
+struct Noise {
+    permutation: [u8; 512],
+}
+
+impl Noise {
+    fn new(seed: u64) -> Self {
+        let mut table: [u8; 256] = std::array::from_fn(|i| i as u8);
+        let mut rng = Rng(seed ^ 0x6e6f_6973_6521);
+        for i in (1..table.len()).rev() {
+            table.swap(i, (rng.next() % (i as u64 + 1)) as usize);
+        }
+        Self {
+            permutation: std::array::from_fn(|i| table[i % 256]),
+        }
+    }
+
+    fn gradient(&self, ix: i32, iy: i32, dx: f32, dy: f32) -> f32 {
+        let hash =
+            self.permutation[self.permutation[(ix & 255) as usize] as usize + (iy & 255) as usize];
+        let angle = hash as f32 / 256.0 * TAU;
+        let (sin, cos) = angle.sin_cos();
+        dx * cos + dy * sin
+    }
+
+    fn get(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (ix, iy) = (x0 as i32, y0 as i32);
+        let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let (u, v) = (fade(dx), fade(dy));
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let top = lerp(
+            self.gradient(ix, iy, dx, dy),
+            self.gradient(ix + 1, iy, dx - 1.0, dy),
+            u,
+        );
+        let bottom = lerp(
+            self.gradient(ix, iy + 1, dx, dy - 1.0),
+            self.gradient(ix + 1, iy + 1, dx - 1.0, dy - 1.0),
+            u,
+        );
+        lerp(top, bottom, v) * std::f32::consts::SQRT_2
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{
+    core::animation::Scene,
+    display::{
+        layout::{Rect, SpanLayout},
+        monitor::Monitor,
+    },
+    image::render::ScalingMode,
+    utils::error::{WallpaperError, WallpaperResult},
+};
+use image::{imageops::FilterType, DynamicImage, RgbaImage};
+use log::debug;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// A slow pan and zoom across the image.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KenBurns {
+    /// Image widths panned per minute.
+    pub speed: f64,
+    /// Magnification range, where 1 shows the whole image.
+    pub zoom: (f64, f64),
+    /// Pan direction in degrees clockwise from rightwards. The pan bounces
+    /// off the edges of the image.
+    pub angle: f64,
+    pub fps: u32,
+}
+
+/// Draws `KenBurns` frames for each output by cropping a moving window out
+/// of the image and resampling it to the output size.
+pub struct KenBurnsScene {
+    params: KenBurns,
+    /// The image, pre-scaled so that no frame downsamples it by much.
+    image: RgbaImage,
+    /// Region of `image` the window moves over, in pixels.
+    canvas: Rect,
+    /// Each output's share of the canvas as fractions of it, and its size.
+    outputs: Vec<(Rect, (usize, usize))>,
+}
+
+impl KenBurnsScene {
+    /// `img` should hold 8-bit channels in the byte order of the outputs'
+    /// shm format. Spanning gives each output its own slice of the moving
+    /// window; stretching shows all of it on every output.
+    pub fn new(
+        params: KenBurns,
+        img: &DynamicImage,
+        mode: &ScalingMode,
+        monitors: &[Monitor],
+    ) -> WallpaperResult<Self> {
+        let (min_zoom, max_zoom) = params.zoom;
+        if !(1.0..=max_zoom).contains(&min_zoom) || !max_zoom.is_finite() {
+            return Err(WallpaperError::InvalidScaling(format!(
+                "Ken Burns zoom must be at least 1 and increasing: {min_zoom},{max_zoom}"
+            )));
+        }
+
+        let (img_width, img_height) = (img.width() as f64, img.height() as f64);
+        let whole = Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+        };
+        let (mut canvas, fractions) = match mode {
+            ScalingMode::Stretch => (
+                Rect {
+                    width: img_width,
+                    height: img_height,
+                    ..whole
+                },
+                vec![whole; monitors.len()],
+            ),
+            ScalingMode::Span { bezels } => {
+                let layout = SpanLayout::new(monitors, bezels);
+                let total = layout.canvas;
+                // Cover the canvas with the image, centered, as a still span does.
+                let scale = (total.width / img_width).max(total.height / img_height);
+                let canvas = Rect {
+                    x: (img_width * scale - total.width) / 2.0 / scale,
+                    y: (img_height * scale - total.height) / 2.0 / scale,
+                    width: total.width / scale,
+                    height: total.height / scale,
+                };
+                let fractions = layout
+                    .outputs
+                    .iter()
+                    .map(|output| Rect {
+                        x: (output.x - total.x) / total.width,
+                        y: (output.y - total.y) / total.height,
+                        width: output.width / total.width,
+                        height: output.height / total.height,
+                    })
+                    .collect();
+                (canvas, fractions)
+            }
+            ScalingMode::Tile { .. } => {
+                return Err(WallpaperError::InvalidScaling(
+                    "Ken Burns needs the stretch or span mode".to_string(),
+                ))
+            }
+        };
+
+        // Shrink the image until the most zoomed-in window still has about
+        // one image pixel per output pixel on the densest output.
+        let density = monitors
+            .iter()
+            .zip(&fractions)
+            .map(|(m, f)| {
+                let across = m.width as f64 / (canvas.width * f.width / max_zoom);
+                let down = m.height as f64 / (canvas.height * f.height / max_zoom);
+                across.max(down)
+            })
+            .fold(0.0, f64::max);
+        let image = if density > 0.0 && density < 1.0 {
+            let width = ((img_width * density).round() as u32).max(1);
+            let height = ((img_height * density).round() as u32).max(1);
+            debug!("Pre-scaling Ken Burns image to {}x{}", width, height);
+            canvas = Rect {
+                x: canvas.x * density,
+                y: canvas.y * density,
+                width: canvas.width * density,
+                height: canvas.height * density,
+            };
+            img.resize_exact(width, height, FilterType::Triangle)
+                .to_rgba8()
+        } else {
+            img.to_rgba8()
+        };
+
+        let outputs = monitors
+            .iter()
+            .zip(fractions)
+            .map(|(m, f)| (f, (m.width as usize, m.height as usize)))
+            .collect();
+        Ok(Self {
+            params,
+            image,
+            canvas,
+            outputs,
+        })
+    }
+
+    /// The visible part of the canvas at `elapsed`, as fractions of it.
+    fn window(&self, elapsed: Duration) -> Rect {
+        let travelled = elapsed.as_secs_f64() * self.params.speed / 60.0;
+        let (min_zoom, max_zoom) = self.params.zoom;
+        // Zoom in and back out once for every two crossings.
+        let zoom = min_zoom + (max_zoom - min_zoom) * bounce(travelled / 2.0);
+        let size = 1.0 / zoom;
+
+        let (sin, cos) = self.params.angle.to_radians().sin_cos();
+        Rect {
+            x: bounce(0.5 + travelled * cos) * (1.0 - size),
+            y: bounce(0.5 + travelled * sin) * (1.0 - size),
+            width: size,
+            height: size,
+        }
+    }
+}
+
+/// Goes from 0 to 1 and back with period 2, like a ball between two walls.
+fn bounce(value: f64) -> f64 {
+    1.0 - (value.rem_euclid(2.0) - 1.0).abs()
+}
+
+impl Scene for KenBurnsScene {
+    fn draw(&self, index: usize, elapsed: Duration, dst: &mut [u8]) {
+        let (fraction, (width, height)) = self.outputs[index];
+        let window = self.window(elapsed);
+        // This output's slice of the window, in image pixels.
+        let src = Rect {
+            x: self.canvas.x + (window.x + fraction.x * window.width) * self.canvas.width,
+            y: self.canvas.y + (window.y + fraction.y * window.height) * self.canvas.height,
+            width: fraction.width * window.width * self.canvas.width,
+            height: fraction.height * window.height * self.canvas.height,
+        };
+        resample(&self.image, src, width, height, dst);
+    }
+
+    fn frame_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.params.fps.max(1)
+    }
+
+    fn size(&self, index: usize) -> (usize, usize) {
+        self.outputs[index].1
+    }
+}
+
+/// Bilinearly samples the `src` region of `image` into a `width`x`height`
+/// frame.
+fn resample(image: &RgbaImage, src: Rect, width: usize, height: usize, dst: &mut [u8]) {
+    let (img_width, img_height) = (image.width() as usize, image.height() as usize);
+    let raw = image.as_raw();
+    let scale_x = src.width / width as f64;
+    let scale_y = src.height / height as f64;
+
+    // Column taps are the same on every row.
+    let columns: Vec<(usize, usize, u32)> = (0..width)
+        .map(|x| {
+            let sx = (src.x + (x as f64 + 0.5) * scale_x - 0.5).clamp(0.0, (img_width - 1) as f64);
+            let x0 = sx as usize;
+            let weight = ((sx - x0 as f64) * 256.0) as u32;
+            (x0 * 4, (x0 + 1).min(img_width - 1) * 4, weight)
+        })
+        .collect();
+
+    dst.par_chunks_mut(width * 4)
+        .take(height)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let sy = (src.y + (y as f64 + 0.5) * scale_y - 0.5).clamp(0.0, (img_height - 1) as f64);
+            let y0 = sy as usize;
+            let wy = ((sy - y0 as f64) * 256.0) as u32;
+            let top = &raw[y0 * img_width * 4..(y0 + 1) * img_width * 4];
+            let y1 = (y0 + 1).min(img_height - 1);
+            let bottom = &raw[y1 * img_width * 4..(y1 + 1) * img_width * 4];
+
+            for (pixel, &(left, right, wx)) in row.chunks_exact_mut(4).zip(&columns) {
+                for c in 0..4 {
+                    let lerp = |a: u8, b: u8, w: u32| a as u32 * (256 - w) + b as u32 * w;
+                    let upper = lerp(top[left + c], top[right + c], wx);
+                    let lower = lerp(bottom[left + c], bottom[right + c], wx);
+                    pixel[c] = ((upper * (256 - wy) + lower * wy + (1 << 15)) >> 16) as u8;
+                }
+            }
+        });
+}
@@ -1,5 +1,12 @@
-use crate::{WallpaperError, WallpaperResult};
-use dashmap::DashMap;
+use crate::{
+    core::{
+        cache::{CacheStats, Lru},
+        swizzle::PixelOrder,
+    },
+    image::source::SourceId,
+    utils::config::Config,
+    WallpaperError, WallpaperResult,
+};
 use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageBuffer, Rgba};
 use log::debug;
 use once_cell::sync::Lazy;
@@ -8,32 +15,92 @@ use rayon::prelude::*;
 use std::{
     fs::File,
     io::{BufReader, Read},
+    path::Path,
     sync::Arc,
     time::Instant,
 };
 use turbojpeg::{Decompressor, PixelFormat};
 
-static IMAGE_CACHE: Lazy<DashMap<String, Arc<DynamicImage>>> = Lazy::new(DashMap::new);
+type ImageCache = Lru<(SourceId, PixelOrder), Arc<DynamicImage>>;
+
+static IMAGE_CACHE: Lazy<Mutex<ImageCache>> =
+    Lazy::new(|| Mutex::new(Lru::new(Config::default().image_cache_budget)));
 static DECOMPRESSOR: Lazy<Mutex<Decompressor>> =
     Lazy::new(|| Mutex::new(Decompressor::new().expect("Failed to create JPEG decompressor")));
 
 pub struct ImageLoader;
 
 impl ImageLoader {
+    pub fn set_cache_budget(bytes: usize) {
+        let evicted = IMAGE_CACHE.lock().set_budget(bytes);
+        if !evicted.is_empty() {
+            debug!(
+                "Evicted {} images to fit the new cache budget",
+                evicted.len()
+            );
+        }
+    }
+
+    pub fn cache_stats() -> CacheStats {
+        IMAGE_CACHE.lock().stats()
+    }
+
+    pub fn clear_cache() {
+        IMAGE_CACHE.lock().clear();
+    }
+
     pub fn preload(path: &str) -> WallpaperResult<Arc<DynamicImage>> {
-        if let Some(cached) = IMAGE_CACHE.get(path) {
-            return Ok(cached.clone());
+        Self::preload_as(path, PixelOrder::Rgba)
+    }
+
+    /// Decodes `path` with its channels laid out in `order`. The result is
+    /// still an RGBA image as far as `image` is concerned, which is fine for
+    /// scaling and cropping since those treat every channel alike.
+    pub fn preload_as(path: &str, order: PixelOrder) -> WallpaperResult<Arc<DynamicImage>> {
+        Self::load(&SourceId::new(path, false)?, order)
+    }
+
+    /// Like `preload_as`, but cached on the identity of the file rather than
+    /// its path so that a changed file is decoded again.
+    pub fn load(source: &SourceId, order: PixelOrder) -> WallpaperResult<Arc<DynamicImage>> {
+        let key = (source.clone(), order);
+        {
+            let mut cache = IMAGE_CACHE.lock();
+            if let Some(cached) = cache.get(&key) {
+                return Ok(cached.clone());
+            }
+            let stale = cache.retain(|(cached, _)| !source.supersedes(cached));
+            if !stale.is_empty() {
+                debug!("Dropped {} stale decodes of {}", stale.len(), source.path);
+            }
         }
 
         let start = Instant::now();
+        let img = Arc::new(Self::decode(Path::new(&source.path), order)?);
+        IMAGE_CACHE
+            .lock()
+            .insert(key, img.clone(), img.as_bytes().len());
+        debug!("Image loaded in {:?}", start.elapsed());
+        Ok(img)
+    }
 
+    /// Decodes `path` without caching it, for images shown only once.
+    pub fn decode(path: &Path, order: PixelOrder) -> WallpaperResult<DynamicImage> {
         let mut file = BufReader::with_capacity(1024 * 1024, File::open(path)?);
-        let mut jpeg_data = Vec::with_capacity(1024 * 1024);
-        file.read_to_end(&mut jpeg_data)?;
+        let mut data = Vec::with_capacity(1024 * 1024);
+        file.read_to_end(&mut data)?;
+
+        if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            Self::decode_jpeg(&data, order)
+        } else {
+            Self::decode_other(&data, order)
+        }
+    }
 
+    fn decode_jpeg(data: &[u8], order: PixelOrder) -> WallpaperResult<DynamicImage> {
         let mut decompressor = DECOMPRESSOR.lock();
         let header = decompressor
-            .read_header(&jpeg_data)
+            .read_header(data)
             .map_err(|e| WallpaperError::Memory(e.to_string()))?;
 
         let width = header.width;
@@ -42,24 +109,49 @@ impl ImageLoader {
         let mut output = vec![0u8; width * height * 4];
         decompressor
             .decompress(
-                &jpeg_data,
+                data,
                 turbojpeg::Image {
                     pixels: &mut output,
                     width,
                     height,
-                    format: PixelFormat::RGBA,
+                    format: match order {
+                        PixelOrder::Rgba => PixelFormat::RGBA,
+                        PixelOrder::Bgra => PixelFormat::BGRX,
+                    },
                     pitch: width * 4,
                 },
             )
             .map_err(|e| WallpaperError::Memory(e.to_string()))?;
 
-        let img = ImageBuffer::from_raw(width as u32, height as u32, output)
+        ImageBuffer::from_raw(width as u32, height as u32, output)
             .map(DynamicImage::ImageRgba8)
-            .ok_or_else(|| WallpaperError::Memory("Failed to create image buffer".into()))?;
+            .ok_or_else(|| WallpaperError::Memory("Failed to create image buffer".into()))
+    }
 
-        let img = Arc::new(img);
-        IMAGE_CACHE.insert(path.to_string(), img.clone());
-        debug!("Image loaded in {:?}", start.elapsed());
+    /// Decodes any other format `image` supports. Sources with more than 8
+    /// bits per channel stay at 16 bits or float so they can be dithered
+    /// once scaled to the output.
+    fn decode_other(data: &[u8], order: PixelOrder) -> WallpaperResult<DynamicImage> {
+        let img = image::load_from_memory(data)?;
+        let color = img.color();
+        let bits = color.bits_per_pixel() / color.channel_count() as u16;
+        let mut img = match img {
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                DynamicImage::ImageRgba32F(img.into_rgba32f())
+            }
+            img if bits > 8 => DynamicImage::ImageRgba16(img.into_rgba16()),
+            img => DynamicImage::ImageRgba8(img.into_rgba8()),
+        };
+        debug!("Decoded {:?} image as {:?}", color, img.color());
+
+        if order == PixelOrder::Bgra {
+            match &mut img {
+                DynamicImage::ImageRgba8(buf) => swap_red_blue(buf),
+                DynamicImage::ImageRgba16(buf) => swap_red_blue(buf),
+                DynamicImage::ImageRgba32F(buf) => swap_red_blue(buf),
+                _ => unreachable!("converted to RGBA above"),
+            }
+        }
         Ok(img)
     }
 
@@ -75,7 +167,12 @@ impl ImageLoader {
             return Ok(img.clone());
         }
 
-        let scaled = if img_width > width || img_height > height {
+        // The nearest-neighbour fast path would throw away the precision of
+        // 16-bit and float images.
+        let is_rgba8 = matches!(img, DynamicImage::ImageRgba8(_));
+        let scaled = if !is_rgba8 {
+            img.resize_exact(width, height, FilterType::Triangle)
+        } else if img_width > width || img_height > height {
             let mut target = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width, height);
             let source = img.to_rgba8();
             let src_pixels = source.as_raw();
@@ -100,3 +197,7 @@ impl ImageLoader {
         Ok(scaled)
     }
 }
+
+fn swap_red_blue<S: Send>(pixels: &mut [S]) {
+    pixels.par_chunks_exact_mut(4).for_each(|px| px.swap(0, 2));
+}
@@ -0,0 +1,219 @@
+use crate::{
+    display::{
+        layout::{Bezel, SpanLayout},
+        monitor::Monitor,
+    },
+    image::{dither::Dither, loader::ImageLoader},
+    utils::{error::WallpaperResult, hash::StableHasher},
+};
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageBuffer, Pixel, RgbaImage};
+use log::debug;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::hash::Hasher;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ScalingMode {
+    /// Scale the image to fill each output independently.
+    #[default]
+    Stretch,
+    /// Treat all outputs as one canvas and give each its slice of the image.
+    Span { bezels: Vec<Bezel> },
+    /// Repeat the image at `scale`, shifted by `offset` pixels. With `align`
+    /// the pattern is anchored to the layout so it continues across outputs.
+    Tile {
+        scale: f64,
+        offset: (i32, i32),
+        align: bool,
+    },
+}
+
+/// Turns a decoded image into the pixels shown on each output.
+pub struct Renderer<'a> {
+    mode: &'a ScalingMode,
+    layout: Option<SpanLayout>,
+    tile_origins: Vec<(i64, i64)>,
+    dither: Dither,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(mode: &'a ScalingMode, dither: Dither, monitors: &[Monitor]) -> Self {
+        let layout = match mode {
+            ScalingMode::Span { bezels } => Some(SpanLayout::new(monitors, bezels)),
+            _ => None,
+        };
+        let tile_origins = match mode {
+            ScalingMode::Tile { offset, align, .. } => monitors
+                .iter()
+                .map(|m| {
+                    let (mut x, mut y) = (offset.0 as i64, offset.1 as i64);
+                    if *align {
+                        let (logical_x, logical_y) = m.logical_position();
+                        let (logical_width, logical_height) = m.logical_size();
+                        x += logical_x as i64 * m.width as i64 / logical_width.max(1) as i64;
+                        y += logical_y as i64 * m.height as i64 / logical_height.max(1) as i64;
+                    }
+                    (x, y)
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Self {
+            mode,
+            layout,
+            tile_origins,
+            dither,
+        }
+    }
+
+    pub fn render(
+        &self,
+        img: &DynamicImage,
+        index: usize,
+        monitor: &Monitor,
+    ) -> WallpaperResult<DynamicImage> {
+        let (width, height) = (monitor.width as u32, monitor.height as u32);
+
+        if let ScalingMode::Tile { scale, .. } = self.mode {
+            return Ok(tile(img, *scale, self.tile_origins[index], width, height));
+        }
+
+        match &self.layout {
+            Some(layout) => {
+                let (img_width, img_height) = img.dimensions();
+                let rect = layout.source_rect(index, img_width, img_height);
+                let x = (rect.x.round() as u32).min(img_width - 1);
+                let y = (rect.y.round() as u32).min(img_height - 1);
+                let crop_width = (rect.width.round() as u32).clamp(1, img_width - x);
+                let crop_height = (rect.height.round() as u32).clamp(1, img_height - y);
+
+                let cropped = img.crop_imm(x, y, crop_width, crop_height);
+                ImageLoader::scale_image(&cropped, width, height)
+            }
+            None => ImageLoader::scale_image(img, width, height),
+        }
+    }
+
+    /// Renders output `index` and quantizes it to the 8-bit frame uploaded
+    /// to the compositor.
+    pub fn render_frame(
+        &self,
+        img: &DynamicImage,
+        index: usize,
+        monitor: &Monitor,
+    ) -> WallpaperResult<RgbaImage> {
+        let rendered = self.render(img, index, monitor)?;
+        Ok(self.dither.quantize(&rendered))
+    }
+
+    /// Identifies everything besides the source image and output size that
+    /// affects the pixels rendered for output `index`.
+    pub fn cache_tag(&self, index: usize) -> u64 {
+        // Stable, since the tag is part of disk cache keys.
+        let mut hasher = StableHasher::default();
+        hasher.write_u8(match self.mode {
+            ScalingMode::Stretch => 0,
+            ScalingMode::Span { .. } => 1,
+            ScalingMode::Tile { .. } => 2,
+        });
+        hasher.write_u8(self.dither.method as u8);
+        hasher.write_u8(self.dither.space as u8);
+        if let ScalingMode::Tile { scale, .. } = self.mode {
+            let (x, y) = self.tile_origins[index];
+            hasher.write_u64(scale.to_bits());
+            hasher.write_i64(x);
+            hasher.write_i64(y);
+        }
+        if let Some(layout) = &self.layout {
+            for value in [
+                layout.canvas.width,
+                layout.canvas.height,
+                layout.outputs[index].x - layout.canvas.x,
+                layout.outputs[index].y - layout.canvas.y,
+            ] {
+                hasher.write_u64(value.to_bits());
+            }
+        }
+        hasher.finish()
+    }
+}
+
+/// Tiles grow to at most this many outputs per side; beyond that only a
+/// sliver of one tile would show, at a huge resize cost.
+const MAX_TILE_OUTPUTS: f64 = 4.0;
+
+/// Repeats `img`, scaled by `scale`, over a `width`x`height` output whose
+/// top-left pixel sits at `origin` in pattern space. 16-bit and float images
+/// are tiled at their own precision.
+fn tile(
+    img: &DynamicImage,
+    scale: f64,
+    origin: (i64, i64),
+    width: u32,
+    height: u32,
+) -> DynamicImage {
+    let (img_width, img_height) = img.dimensions();
+    let max_scale = (MAX_TILE_OUTPUTS * width as f64 / img_width as f64)
+        .min(MAX_TILE_OUTPUTS * height as f64 / img_height as f64);
+    if scale > max_scale {
+        debug!("Capping tile scale {} to {}", scale, max_scale);
+    }
+    let scale = scale.min(max_scale);
+    let tile_width = ((img_width as f64 * scale).round() as u32).max(1);
+    let tile_height = ((img_height as f64 * scale).round() as u32).max(1);
+
+    let resized;
+    let pattern = if (tile_width, tile_height) == (img_width, img_height) {
+        img
+    } else {
+        resized = img.resize_exact(tile_width, tile_height, FilterType::CatmullRom);
+        &resized
+    };
+
+    match pattern {
+        DynamicImage::ImageRgba16(buf) => {
+            DynamicImage::ImageRgba16(repeat(buf, origin, width, height))
+        }
+        DynamicImage::ImageRgba32F(buf) => {
+            DynamicImage::ImageRgba32F(repeat(buf, origin, width, height))
+        }
+        pattern => DynamicImage::ImageRgba8(repeat(&pattern.to_rgba8(), origin, width, height)),
+    }
+}
+
+fn repeat<P>(
+    pattern: &ImageBuffer<P, Vec<P::Subpixel>>,
+    origin: (i64, i64),
+    width: u32,
+    height: u32,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Send + Sync,
+{
+    let (tile_width, tile_height) = pattern.dimensions();
+    let channels = P::CHANNEL_COUNT as usize;
+    let src_stride = tile_width as usize * channels;
+    let start_x = origin.0.rem_euclid(tile_width as i64) as usize;
+    let start_y = origin.1.rem_euclid(tile_height as i64) as usize;
+
+    let mut target = ImageBuffer::<P, Vec<P::Subpixel>>::new(width, height);
+    target
+        .par_chunks_mut(width as usize * channels)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let src_y = (start_y + y) % tile_height as usize;
+            let src_row = &pattern.as_raw()[src_y * src_stride..(src_y + 1) * src_stride];
+
+            let mut src_x = start_x * channels;
+            let mut written = 0;
+            while written < row.len() {
+                let len = (src_stride - src_x).min(row.len() - written);
+                row[written..written + len].copy_from_slice(&src_row[src_x..src_x + len]);
+                written += len;
+                src_x = 0;
+            }
+        });
+
+    target
+}
@@ -0,0 +1,61 @@
+use crate::utils::{error::WallpaperResult, hash::StableHasher};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    hash::Hasher,
+    io::Read,
+    os::unix::fs::MetadataExt,
+};
+
+/// Identifies the contents of an image file, so that replacing or editing
+/// the file at a path misses every cache keyed on it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SourceId {
+    pub path: String,
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
+    pub mtime_ns: i64,
+    /// Stable hash of the file contents, for edits that keep size and
+    /// mtime.
+    pub content_hash: Option<u64>,
+}
+
+impl SourceId {
+    pub fn new(path: &str, hash_contents: bool) -> WallpaperResult<Self> {
+        let metadata = fs::metadata(path)?;
+        let content_hash = if hash_contents {
+            Some(hash_file(path)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            path: path.to_string(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.size(),
+            mtime_ns: metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+            content_hash,
+        })
+    }
+
+    /// Whether `other` is a different version of the same path.
+    pub fn supersedes(&self, other: &Self) -> bool {
+        self.path == other.path && self != other
+    }
+}
+
+fn hash_file(path: &str) -> WallpaperResult<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = StableHasher::default();
+    let mut chunk = vec![0u8; 1024 * 1024];
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        hasher.write(&chunk[..read]);
+    }
+    Ok(hasher.finish())
+}
@@ -0,0 +1,515 @@
+use crate::{
+    core::{animation::Scene, swizzle::PixelOrder},
+    display::monitor::Monitor,
+    image::loader::ImageLoader,
+    utils::error::{WallpaperError, WallpaperResult},
+};
+use image::imageops::FilterType;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender, SyncSender},
+    thread,
+    time::Duration,
+};
+
+/// Frames decoded ahead of the one on screen.
+const READ_AHEAD: usize = 4;
+
+/// Frame rate of image sequences unless given.
+const DEFAULT_SEQUENCE_FPS: f64 = 30.0;
+
+/// Largest width or height accepted from a stream header, so a bogus one
+/// can't make us allocate gigabytes per frame.
+const MAX_SIZE: usize = 16384;
+
+/// A looping video, either a `.y4m` file or a directory of numbered images.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Video {
+    pub path: PathBuf,
+    /// Overrides the source's frame rate.
+    pub fps: Option<f64>,
+}
+
+impl Video {
+    pub fn open(&self) -> WallpaperResult<Box<dyn FrameSource>> {
+        if let Some(fps) = self.fps.filter(|fps| !(fps.is_finite() && *fps > 0.0)) {
+            return Err(WallpaperError::InvalidVideo(format!(
+                "Frame rate must be positive: {fps}"
+            )));
+        }
+        if self.path.is_dir() {
+            Ok(Box::new(ImageSequence::open(&self.path, self.fps)?))
+        } else {
+            Ok(Box::new(Y4mSource::open(&self.path, self.fps)?))
+        }
+    }
+}
+
+/// Decodes video frames one after another, independently of the container
+/// and codec.
+pub trait FrameSource: Send {
+    fn size(&self) -> (usize, usize);
+
+    fn frame_rate(&self) -> f64;
+
+    /// Decodes the next frame into `dst`, which holds `width * height * 4`
+    /// bytes laid out as `order`. Returns false at the end of the video.
+    fn read_frame(&mut self, order: PixelOrder, dst: &mut [u8]) -> WallpaperResult<bool>;
+
+    /// Goes back to the first frame.
+    fn rewind(&mut self) -> WallpaperResult<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Chroma {
+    /// Chroma at half resolution both ways.
+    C420,
+    /// Chroma at half horizontal resolution.
+    C422,
+    C444,
+    Mono,
+}
+
+/// An uncompressed YUV4MPEG2 stream with 8-bit samples, converted to RGB
+/// as BT.601 limited range.
+pub struct Y4mSource {
+    reader: BufReader<File>,
+    width: usize,
+    height: usize,
+    fps: f64,
+    chroma: Chroma,
+    /// Offset of the first frame.
+    data_start: u64,
+    /// Planes of the frame being converted.
+    planes: Vec<u8>,
+}
+
+impl Y4mSource {
+    pub fn open(path: &Path, fps: Option<f64>) -> WallpaperResult<Self> {
+        let invalid = |reason: String| {
+            WallpaperError::InvalidVideo(format!("{}: {}", path.display(), reason))
+        };
+        let mut reader = BufReader::with_capacity(1024 * 1024, File::open(path)?);
+        let mut header = Vec::new();
+        reader.read_until(b'\n', &mut header)?;
+        let header = String::from_utf8_lossy(&header);
+        let mut params = header.split_ascii_whitespace();
+        if params.next() != Some("YUV4MPEG2") {
+            return Err(invalid("not a YUV4MPEG2 stream".to_string()));
+        }
+
+        let (mut width, mut height, mut rate, mut chroma) = (0, 0, None, Chroma::C420);
+        for param in params {
+            let Some((tag, value)) = param.split_at_checked(1) else {
+                continue;
+            };
+            match tag {
+                "W" => width = value.parse().unwrap_or(0),
+                "H" => height = value.parse().unwrap_or(0),
+                "F" => {
+                    rate = value
+                        .split_once(':')
+                        .and_then(|(n, d)| Some((n.parse::<f64>().ok()?, d.parse::<f64>().ok()?)))
+                        .filter(|&(_, d)| d > 0.0)
+                        .map(|(n, d)| n / d)
+                }
+                "C" => {
+                    chroma = match value {
+                        "420" | "420jpeg" | "420paldv" | "420mpeg2" => Chroma::C420,
+                        "422" => Chroma::C422,
+                        "444" => Chroma::C444,
+                        "mono" => Chroma::Mono,
+                        other => return Err(invalid(format!("unsupported colorspace {other}"))),
+                    }
+                }
+                _ => {}
+            }
+        }
+        if width == 0 || height == 0 {
+            return Err(invalid("missing frame size".to_string()));
+        }
+        if width > MAX_SIZE || height > MAX_SIZE {
+            return Err(invalid(format!("frame size {width}x{height} is too large")));
+        }
+        let fps = fps
+            .or(rate)
+            .filter(|&fps| fps > 0.0)
+            .ok_or_else(|| invalid("missing frame rate".to_string()))?;
+
+        let data_start = reader.stream_position()?;
+        info!(
+            "Opened {}x{} {:?} video at {:.2} fps",
+            width, height, chroma, fps
+        );
+        let mut source = Self {
+            reader,
+            width,
+            height,
+            fps,
+            chroma,
+            data_start,
+            planes: Vec::new(),
+        };
+        source.planes = vec![0; source.frame_len()];
+        Ok(source)
+    }
+
+    fn chroma_size(&self) -> (usize, usize) {
+        match self.chroma {
+            Chroma::C420 => (self.width.div_ceil(2), self.height.div_ceil(2)),
+            Chroma::C422 => (self.width.div_ceil(2), self.height),
+            Chroma::C444 => (self.width, self.height),
+            Chroma::Mono => (0, 0),
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        let (chroma_width, chroma_height) = self.chroma_size();
+        self.width * self.height + 2 * chroma_width * chroma_height
+    }
+}
+
+impl FrameSource for Y4mSource {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn frame_rate(&self) -> f64 {
+        self.fps
+    }
+
+    fn read_frame(&mut self, order: PixelOrder, dst: &mut [u8]) -> WallpaperResult<bool> {
+        let mut header = Vec::new();
+        if self.reader.read_until(b'\n', &mut header)? == 0 {
+            return Ok(false);
+        }
+        if !header.starts_with(b"FRAME") {
+            return Err(WallpaperError::InvalidVideo(
+                "frame header missing".to_string(),
+            ));
+        }
+        match self.reader.read_exact(&mut self.planes) {
+            // A truncated last frame ends the video.
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            result => result?,
+        }
+
+        let (width, chroma) = (self.width, self.chroma);
+        let (chroma_width, chroma_height) = self.chroma_size();
+        let (luma, rest) = self.planes.split_at(width * self.height);
+        let (u, v) = rest.split_at(chroma_width * chroma_height);
+        let (r, b) = match order {
+            PixelOrder::Rgba => (0, 2),
+            PixelOrder::Bgra => (2, 0),
+        };
+
+        dst.par_chunks_mut(width * 4)
+            .take(self.height)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let luma = &luma[y * width..(y + 1) * width];
+                let chroma_row = match chroma {
+                    Chroma::C420 => y / 2,
+                    _ => y,
+                } * chroma_width;
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let c = 298 * (luma[x] as i32 - 16);
+                    let (d, e) = match chroma {
+                        Chroma::Mono => (0, 0),
+                        Chroma::C444 => (
+                            u[chroma_row + x] as i32 - 128,
+                            v[chroma_row + x] as i32 - 128,
+                        ),
+                        _ => (
+                            u[chroma_row + x / 2] as i32 - 128,
+                            v[chroma_row + x / 2] as i32 - 128,
+                        ),
+                    };
+                    let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+                    pixel[r] = clamp(c + 409 * e);
+                    pixel[1] = clamp(c - 100 * d - 208 * e);
+                    pixel[b] = clamp(c + 516 * d);
+                    pixel[3] = 0xff;
+                }
+            });
+        Ok(true)
+    }
+
+    fn rewind(&mut self) -> WallpaperResult<()> {
+        self.reader.seek(SeekFrom::Start(self.data_start))?;
+        Ok(())
+    }
+}
+
+/// A directory of images played in the order of the numbers in their
+/// names. Frames of another size than the first are scaled to it.
+pub struct ImageSequence {
+    paths: Vec<PathBuf>,
+    next: usize,
+    width: usize,
+    height: usize,
+    fps: f64,
+}
+
+impl ImageSequence {
+    pub fn open(dir: &Path, fps: Option<f64>) -> WallpaperResult<Self> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.is_file() && image::ImageFormat::from_path(path).is_ok())
+            .collect();
+        if paths.is_empty() {
+            return Err(WallpaperError::InvalidVideo(format!(
+                "{}: no images",
+                dir.display()
+            )));
+        }
+        // frame2 before frame10, falling back to the name without numbers.
+        paths.sort_by_cached_key(|path| {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            let digits: String = name.chars().filter(char::is_ascii_digit).collect();
+            (digits.len(), digits, path.clone())
+        });
+
+        let first = ImageLoader::decode(&paths[0], PixelOrder::Rgba)?;
+        let (width, height) = (first.width() as usize, first.height() as usize);
+        let fps = fps.unwrap_or(DEFAULT_SEQUENCE_FPS);
+        info!(
+            "Opened {} frame {}x{} image sequence at {:.2} fps",
+            paths.len(),
+            width,
+            height,
+            fps
+        );
+        Ok(Self {
+            paths,
+            next: 0,
+            width,
+            height,
+            fps,
+        })
+    }
+}
+
+impl FrameSource for ImageSequence {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn frame_rate(&self) -> f64 {
+        self.fps
+    }
+
+    fn read_frame(&mut self, order: PixelOrder, dst: &mut [u8]) -> WallpaperResult<bool> {
+        let Some(path) = self.paths.get(self.next) else {
+            return Ok(false);
+        };
+        self.next += 1;
+        let mut img = ImageLoader::decode(path, order)?;
+        if (img.width() as usize, img.height() as usize) != (self.width, self.height) {
+            debug!("Scaling {} to the sequence size", path.display());
+            img = img.resize_exact(self.width as u32, self.height as u32, FilterType::Triangle);
+        }
+        let img = img.into_rgba8();
+        dst.copy_from_slice(img.as_raw());
+        Ok(true)
+    }
+
+    fn rewind(&mut self) -> WallpaperResult<()> {
+        self.next = 0;
+        Ok(())
+    }
+}
+
+struct VideoFrame {
+    /// Frames since the start, counting on across loops.
+    number: u64,
+    data: Vec<u8>,
+}
+
+/// Decodes frames on a thread of its own, at most `READ_AHEAD` ahead of
+/// playback, looping at the end. Stops once the scene is dropped.
+fn read_ahead(
+    mut source: Box<dyn FrameSource>,
+    order: PixelOrder,
+    frames: SyncSender<VideoFrame>,
+    recycled: Receiver<Vec<u8>>,
+) {
+    let (width, height) = source.size();
+    for number in 0.. {
+        let mut data = recycled
+            .try_recv()
+            .unwrap_or_else(|_| vec![0; width * height * 4]);
+        let mut read = source.read_frame(order, &mut data);
+        if matches!(read, Ok(false)) && number > 0 {
+            read = source
+                .rewind()
+                .and_then(|()| source.read_frame(order, &mut data));
+        }
+        match read {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("Video has no frames");
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to read video frame: {}", e);
+                return;
+            }
+        }
+        if frames.send(VideoFrame { number, data }).is_err() {
+            return;
+        }
+    }
+}
+
+/// The frames playback has reached.
+struct Playback {
+    frames: Receiver<VideoFrame>,
+    recycle: Sender<Vec<u8>>,
+    current: Option<VideoFrame>,
+    /// A frame read ahead of the one due.
+    pending: Option<VideoFrame>,
+}
+
+impl Playback {
+    /// Moves to the latest decoded frame not after `number`. Only waits for
+    /// the decoder when nothing has been shown yet, so a slow decoder
+    /// slows the video down instead of the daemon.
+    fn seek(&mut self, number: u64) -> Option<&VideoFrame> {
+        if self.current.is_none() {
+            self.current = self.frames.recv().ok();
+        }
+        while self.current.as_ref().is_some_and(|c| c.number < number) {
+            let next = match self.pending.take() {
+                Some(frame) => frame,
+                None => match self.frames.try_recv() {
+                    Ok(frame) => frame,
+                    Err(_) => break,
+                },
+            };
+            if next.number > number {
+                self.pending = Some(next);
+                break;
+            }
+            if let Some(old) = self.current.replace(next) {
+                let _ = self.recycle.send(old.data);
+            }
+        }
+        self.current.as_ref()
+    }
+}
+
+/// Plays a `FrameSource`, each output showing the centered part of the
+/// video with its aspect ratio, scaled up by the compositor.
+pub struct VideoScene {
+    width: usize,
+    fps: f64,
+    /// Each output's crop of the video, as x, y, width and height.
+    crops: Vec<(usize, usize, usize, usize)>,
+    playback: Mutex<Playback>,
+}
+
+impl VideoScene {
+    /// Frames are uploaded as they are decoded, so `order` should match
+    /// the outputs' shm format.
+    pub fn new(
+        source: Box<dyn FrameSource>,
+        order: PixelOrder,
+        monitors: &[Monitor],
+    ) -> WallpaperResult<Self> {
+        let (width, height) = source.size();
+        let fps = source.frame_rate();
+        let crops = monitors
+            .iter()
+            .map(|m| {
+                let aspect = m.width as f64 / m.height.max(1) as f64;
+                if width as f64 / height as f64 > aspect {
+                    let cropped = ((height as f64 * aspect).round() as usize).clamp(1, width);
+                    ((width - cropped) / 2, 0, cropped, height)
+                } else {
+                    let cropped = ((width as f64 / aspect).round() as usize).clamp(1, height);
+                    (0, (height - cropped) / 2, width, cropped)
+                }
+            })
+            .collect();
+
+        let (frame_tx, frame_rx) = mpsc::sync_channel(READ_AHEAD);
+        let (recycle_tx, recycle_rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("video-decode".to_string())
+            .spawn(move || read_ahead(source, order, frame_tx, recycle_rx))?;
+
+        Ok(Self {
+            width,
+            fps,
+            crops,
+            playback: Mutex::new(Playback {
+                frames: frame_rx,
+                recycle: recycle_tx,
+                current: None,
+                pending: None,
+            }),
+        })
+    }
+}
+
+impl Scene for VideoScene {
+    fn draw(&self, index: usize, elapsed: Duration, dst: &mut [u8]) {
+        let (x, y, width, height) = self.crops[index];
+        let mut playback = self.playback.lock();
+        let Some(frame) = playback.seek((elapsed.as_secs_f64() * self.fps) as u64) else {
+            return;
+        };
+        let stride = self.width * 4;
+        dst.par_chunks_mut(width * 4)
+            .take(height)
+            .enumerate()
+            .for_each(|(row, out)| {
+                let start = (y + row) * stride + x * 4;
+                out.copy_from_slice(&frame.data[start..start + width * 4]);
+            });
+    }
+
+    fn frame_interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.fps)
+    }
+
+    fn size(&self, index: usize) -> (usize, usize) {
+        let (_, _, width, height) = self.crops[index];
+        (width, height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn open_header(header: &str) -> WallpaperResult<Y4mSource> {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "{header}").unwrap();
+        Y4mSource::open(file.path(), None)
+    }
+
+    #[test]
+    fn skips_unknown_and_multibyte_params() {
+        let source = open_header("YUV4MPEG2 W4 H2 F30:1 é Ip XYSCSS=420").unwrap();
+        assert_eq!(source.size(), (4, 2));
+        assert_eq!(source.frame_rate(), 30.0);
+    }
+
+    #[test]
+    fn rejects_oversized_and_missing_sizes() {
+        assert!(open_header("YUV4MPEG2 W100000 H2 F30:1").is_err());
+        assert!(open_header("YUV4MPEG2 W2 H100000 F30:1").is_err());
+        assert!(open_header("YUV4MPEG2 W4 F30:1").is_err());
+        assert!(open_header("YUV4MPEG2 W4 H2").is_err());
+    }
+}
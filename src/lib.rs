@@ -1,26 +1,47 @@
 pub mod core {
+    pub mod animation;
     pub mod app;
     pub mod backend;
     pub mod buffer;
     pub mod cache;
     pub mod daemon;
+    pub mod damage;
+    pub mod disk_cache;
+    pub mod easing;
+    pub mod effect;
     pub mod ipc;
     pub mod pool;
+    pub mod presentation;
     pub mod shm;
+    pub mod slideshow;
+    pub mod swizzle;
+    pub mod transition;
 }
 
 pub mod utils {
     pub mod cli;
+    pub mod config;
     pub mod error;
+    pub mod hash;
+    pub mod playlist;
+    pub mod power;
     pub mod wayland;
 }
 
 pub mod display {
+    pub mod layout;
     pub mod monitor;
 }
 
 pub mod image {
+    pub mod color;
+    pub mod dither;
+    pub mod generate;
+    pub mod ken_burns;
     pub mod loader;
+    pub mod render;
+    pub mod source;
+    pub mod video;
 }
 
 pub use core::{app::App, daemon::Daemon};
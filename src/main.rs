@@ -1,11 +1,17 @@
 use clap::Parser;
+use std::path::Path;
 use wallpaper::{
     core::{
         daemon::Daemon,
-        ipc::{IpcClient, IpcMessage},
+        ipc::{IpcClient, IpcMessage, IpcResponse},
+        slideshow::{SlideSource, Slideshow},
     },
-    utils::cli::{Cli, Command},
-    WallpaperResult,
+    utils::{
+        cli::{CacheAction, Cli, Command, Mode},
+        config::Config,
+        playlist,
+    },
+    WallpaperError, WallpaperResult,
 };
 
 #[tokio::main]
@@ -14,17 +20,200 @@ async fn main() -> WallpaperResult<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Command::SetWallpaper { image, monitor } => {
-            let msg = IpcMessage::SetWallpaper { image, monitor };
-            IpcClient::send_message(&msg).await?;
+        Command::SetWallpaper {
+            image,
+            color,
+            monitor,
+            mode,
+            dither,
+            ken_burns,
+            generator,
+            video,
+            transition,
+        } => {
+            let transition = transition.transition();
+            // The daemon doesn't share our working directory.
+            let msg = match (image, color, generator.generator(), video.video()) {
+                (_, _, _, Some(mut video)) => {
+                    video.path = std::fs::canonicalize(&video.path)?;
+                    IpcMessage::SetVideo { video, monitor }
+                }
+                (_, _, Some(generator), None) => IpcMessage::SetGenerated {
+                    generator,
+                    monitor,
+                    dither: dither.dither(),
+                },
+                (_, Some(color), None, None) => IpcMessage::SetColor {
+                    color,
+                    monitor,
+                    transition,
+                },
+                (Some(image), None, None, None) => IpcMessage::SetWallpaper {
+                    image: std::fs::canonicalize(image)?,
+                    monitor,
+                    mode: mode.scaling_mode()?,
+                    dither: dither.dither(),
+                    ken_burns: ken_burns.ken_burns(),
+                    transition,
+                },
+                (None, None, None, None) => {
+                    unreachable!("clap requires an image, a color, a generator or a video")
+                }
+            };
+            send(&msg).await?;
         }
-        Command::Daemon { start } => {
+        Command::Daemon {
+            start,
+            cache_budget,
+            image_cache_budget,
+            hash_contents,
+            disk_cache_budget,
+            disk_cache_max_age,
+            animation_cpu_budget,
+            idle_timeout,
+            pause_on_battery,
+            power_supply_path,
+        } => {
             if start {
-                let daemon = Daemon::new().await?;
+                let config = Config {
+                    cache_budget,
+                    image_cache_budget,
+                    hash_contents,
+                    disk_cache_budget: disk_cache_budget as u64,
+                    disk_cache_max_age,
+                    animation_cpu_budget,
+                    idle_timeout,
+                    pause_on_battery,
+                    power_supply_path,
+                };
+                let daemon = Daemon::new(config).await?;
                 daemon.run().await?;
             }
         }
+        Command::Cache { action } => {
+            let msg = match action {
+                CacheAction::Stats => IpcMessage::CacheStats,
+                CacheAction::Clear => IpcMessage::CacheClear,
+            };
+            if let IpcResponse::CacheStats {
+                buffers,
+                images,
+                disk,
+            } = send(&msg).await?
+            {
+                println!("buffers: {buffers}");
+                println!("images:  {images}");
+                match disk {
+                    Some(disk) => println!("disk:    {disk}"),
+                    None => println!("disk:    disabled"),
+                }
+            }
+        }
+        Command::Stats => {
+            if let IpcResponse::FrameStats(outputs) = send(&IpcMessage::FrameStats).await? {
+                for (name, stats) in outputs {
+                    println!("{name}: {stats}");
+                }
+            }
+        }
+        Command::Slideshow {
+            dir,
+            interval,
+            shuffle,
+            recursive,
+            independent,
+            mode,
+            dither,
+            transition,
+            status,
+            stop,
+        } => {
+            if status {
+                match send(&IpcMessage::SlideshowStatus).await? {
+                    IpcResponse::Slideshow(Some(status)) => println!("{status}"),
+                    _ => println!("No slideshow running"),
+                }
+            } else if stop {
+                send(&IpcMessage::StopSlideshow).await?;
+            } else {
+                let dir = dir.expect("clap requires a directory");
+                let slideshow = Slideshow {
+                    source: SlideSource::Dir {
+                        // The daemon runs elsewhere, so relative paths won't do.
+                        path: std::fs::canonicalize(&dir)?,
+                        recursive,
+                    },
+                    interval,
+                    shuffle,
+                    independent,
+                    mode: mode.scaling_mode()?,
+                    transition: transition.transition(),
+                    dither: dither.dither(),
+                };
+                send(&IpcMessage::StartSlideshow(slideshow)).await?;
+            }
+        }
+        Command::Playlist {
+            file,
+            interval,
+            shuffle,
+            independent,
+            mode,
+            dither,
+            transition,
+            check,
+        } => {
+            let file = std::fs::canonicalize(&file)?;
+            let text = std::fs::read_to_string(&file)?;
+            let dir = file.parent().unwrap_or(Path::new("/"));
+            let defaults = playlist::Defaults {
+                span: mode.scaling_mode_as(Mode::Span),
+                tile: mode.scaling_mode_as(Mode::Tile),
+                mode: mode.scaling_mode()?,
+                transition: transition.transition(),
+            };
+            let slides = match playlist::parse(&text, dir, &defaults) {
+                Ok(slides) if slides.is_empty() => {
+                    return Err(WallpaperError::InvalidPlaylist(format!(
+                        "{} has no entries",
+                        file.display()
+                    )));
+                }
+                Ok(slides) => slides,
+                Err(errors) => {
+                    for error in &errors {
+                        eprintln!("{}: {}", file.display(), error);
+                    }
+                    return Err(WallpaperError::InvalidPlaylist(format!(
+                        "{} bad lines in {}",
+                        errors.len(),
+                        file.display()
+                    )));
+                }
+            };
+            if check {
+                println!("{}: {} entries", file.display(), slides.len());
+                return Ok(());
+            }
+            let slideshow = Slideshow {
+                source: SlideSource::Playlist { path: file, slides },
+                interval,
+                shuffle,
+                independent,
+                mode: defaults.mode,
+                transition: defaults.transition,
+                dither: dither.dither(),
+            };
+            send(&IpcMessage::StartSlideshow(slideshow)).await?;
+        }
     }
 
     Ok(())
 }
+
+async fn send(msg: &IpcMessage) -> WallpaperResult<IpcResponse> {
+    match IpcClient::send_message(msg).await? {
+        IpcResponse::Error(e) => Err(WallpaperError::Daemon(e)),
+        response => Ok(response),
+    }
+}
@@ -1,5 +1,21 @@
-use clap::Parser;
-use std::path::PathBuf;
+use crate::{
+    core::{
+        easing::Easing,
+        transition::{Transition, TransitionKind},
+    },
+    display::layout::Bezel,
+    image::{
+        color::Color,
+        dither::{Dither, DitherMethod, DitherSpace},
+        generate::{Generator, GeneratorKind},
+        ken_burns::KenBurns,
+        render::ScalingMode,
+        video::Video,
+    },
+    utils::error::{WallpaperError, WallpaperResult},
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::{path::PathBuf, time::Duration};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -8,22 +24,461 @@ pub struct Cli {
     pub command: Command,
 }
 
+// Parsed once per run, so the size of `set` doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Parser)]
 pub enum Command {
     #[command(name = "set")]
     SetWallpaper {
         /// Path to the wallpaper image
-        #[arg(short, long)]
-        image: PathBuf,
+        #[arg(short, long, required_unless_present_any = ["color", "generate", "video"])]
+        image: Option<PathBuf>,
+
+        /// Solid color to fill the outputs with, e.g. '#1e1e2e'
+        #[arg(short, long, conflicts_with = "image")]
+        color: Option<Color>,
 
         /// Monitor to set wallpaper on (default: all)
         #[arg(short, long)]
         monitor: Option<String>,
+
+        #[command(flatten)]
+        mode: ModeArgs,
+
+        #[command(flatten)]
+        dither: DitherArgs,
+
+        #[command(flatten)]
+        ken_burns: KenBurnsArgs,
+
+        #[command(flatten)]
+        generator: GeneratorArgs,
+
+        #[command(flatten)]
+        video: VideoArgs,
+
+        #[command(flatten)]
+        transition: TransitionArgs,
     },
 
     #[command(name = "daemon")]
     Daemon {
         #[arg(short, long)]
         start: bool,
+
+        /// Memory kept for rendered wallpapers, e.g. 512MiB
+        #[arg(long, default_value = "256MiB", value_parser = parse_size)]
+        cache_budget: usize,
+
+        /// Memory kept for decoded images, e.g. 512MiB
+        #[arg(long, default_value = "256MiB", value_parser = parse_size)]
+        image_cache_budget: usize,
+
+        /// Also key caches on a hash of each image's contents
+        #[arg(long)]
+        hash_contents: bool,
+
+        /// Disk space kept for rendered frames across restarts, 0 to disable
+        #[arg(long, default_value = "1GiB", value_parser = parse_size)]
+        disk_cache_budget: usize,
+
+        /// Drop frames from the disk cache after this long unused, e.g. 30d
+        #[arg(long, default_value = "30d", value_parser = humantime::parse_duration)]
+        disk_cache_max_age: Duration,
+
+        /// Share of one CPU core animated wallpapers may use, e.g. 25%
+        #[arg(long, default_value = "25%", value_parser = parse_share)]
+        animation_cpu_budget: f64,
+
+        /// Pause animations after this long without input, 0 to never pause
+        #[arg(long, default_value = "1m", value_parser = humantime::parse_duration)]
+        idle_timeout: Duration,
+
+        /// Pause animations while running on battery
+        #[arg(long)]
+        pause_on_battery: bool,
+
+        /// Directory listing the power supplies, as in sysfs
+        #[arg(long, default_value = "/sys/class/power_supply")]
+        power_supply_path: PathBuf,
     },
+
+    /// Inspect or empty the daemon's caches
+    #[command(name = "cache")]
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+
+    /// Print presented and dropped frame counts for each output
+    #[command(name = "stats")]
+    Stats,
+
+    /// Cycle through the images in a directory
+    #[command(name = "slideshow")]
+    Slideshow {
+        /// Directory to take images from
+        #[arg(short, long, required_unless_present_any = ["status", "stop"])]
+        dir: Option<PathBuf>,
+
+        /// How long each image stays up, e.g. 15m
+        #[arg(long, default_value = "15m", value_parser = humantime::parse_duration)]
+        interval: Duration,
+
+        /// Show the images in random order, reshuffled on every pass
+        #[arg(long)]
+        shuffle: bool,
+
+        /// Include images in subdirectories
+        #[arg(long)]
+        recursive: bool,
+
+        /// Give every output its own sequence
+        #[arg(long)]
+        independent: bool,
+
+        #[command(flatten)]
+        mode: ModeArgs,
+
+        #[command(flatten)]
+        dither: DitherArgs,
+
+        #[command(flatten)]
+        transition: TransitionArgs,
+
+        /// Print the running slideshow or playlist instead of starting one
+        #[arg(long, conflicts_with_all = ["dir", "stop"])]
+        status: bool,
+
+        /// Stop the running slideshow or playlist, keeping the current images
+        #[arg(long, conflicts_with = "dir")]
+        stop: bool,
+    },
+
+    /// Play a playlist file, looping; see `slideshow` for its status
+    #[command(name = "playlist")]
+    Playlist {
+        /// Playlist with one `PATH [duration=15m] [mode=span] [transition=fade]
+        /// [outputs=DP-1,DP-2]` entry per line
+        file: PathBuf,
+
+        /// How long entries without a duration stay up
+        #[arg(long, default_value = "15m", value_parser = humantime::parse_duration)]
+        interval: Duration,
+
+        /// Play the entries in random order, reshuffled on every pass
+        #[arg(long)]
+        shuffle: bool,
+
+        /// Give every output its own sequence
+        #[arg(long)]
+        independent: bool,
+
+        #[command(flatten)]
+        mode: ModeArgs,
+
+        #[command(flatten)]
+        dither: DitherArgs,
+
+        #[command(flatten)]
+        transition: TransitionArgs,
+
+        /// Only check the playlist, without playing it
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+#[derive(Clone, Copy, Subcommand)]
+pub enum CacheAction {
+    /// Print entry counts, memory use and hit rates
+    Stats,
+    /// Drop every cached image and buffer, in memory and on disk
+    Clear,
+}
+
+#[derive(Args)]
+pub struct ModeArgs {
+    /// How the image is fitted to the outputs
+    #[arg(long, value_enum, default_value_t = Mode::Stretch)]
+    mode: Mode,
+
+    /// Bezel width added around outputs when spanning, as
+    /// [OUTPUT:]SIZE with SIZE in mm or px (e.g. DP-1:12mm, 40px)
+    #[arg(long)]
+    bezel: Vec<Bezel>,
+
+    /// Scale applied to the image before tiling
+    #[arg(long, default_value_t = 1.0, value_parser = parse_tile_scale)]
+    tile_scale: f64,
+
+    /// Pixel offset of the tiling pattern, as X,Y
+    #[arg(long, default_value = "0,0", value_parser = parse_offset)]
+    tile_offset: (i32, i32),
+
+    /// Continue the pattern across outputs using their layout positions
+    #[arg(long)]
+    tile_align: bool,
+}
+
+#[derive(Args)]
+pub struct DitherArgs {
+    /// How frames with more than 8 bits per channel are quantized
+    #[arg(long, value_enum, default_value_t = DitherMethod::Auto)]
+    dither: DitherMethod,
+
+    /// Color space the dither is applied in
+    #[arg(long, value_enum, default_value_t = DitherSpace::Srgb)]
+    dither_space: DitherSpace,
+}
+
+impl DitherArgs {
+    pub fn dither(&self) -> Dither {
+        Dither {
+            method: self.dither,
+            space: self.dither_space,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct KenBurnsArgs {
+    /// Slowly pan and zoom across the image (stretch and span modes)
+    #[arg(long, conflicts_with = "color")]
+    ken_burns: bool,
+
+    /// Pan speed in image widths per minute
+    #[arg(long, default_value_t = 0.5, requires = "ken_burns")]
+    ken_burns_speed: f64,
+
+    /// Zoom range as MIN,MAX, where 1 shows the whole image
+    #[arg(long, default_value = "1.1,1.4", value_parser = parse_zoom, requires = "ken_burns")]
+    ken_burns_zoom: (f64, f64),
+
+    /// Pan direction in degrees, clockwise from left-to-right; the pan
+    /// bounces off the edges of the image
+    #[arg(
+        long,
+        default_value_t = 30.0,
+        allow_hyphen_values = true,
+        requires = "ken_burns"
+    )]
+    ken_burns_angle: f64,
+
+    /// Frames drawn per second
+    #[arg(long, default_value_t = 10, requires = "ken_burns")]
+    ken_burns_fps: u32,
+}
+
+impl KenBurnsArgs {
+    pub fn ken_burns(&self) -> Option<KenBurns> {
+        self.ken_burns.then_some(KenBurns {
+            speed: self.ken_burns_speed,
+            zoom: self.ken_burns_zoom,
+            angle: self.ken_burns_angle,
+            fps: self.ken_burns_fps,
+        })
+    }
+}
+
+#[derive(Args)]
+pub struct VideoArgs {
+    /// Looping video to play: a .y4m file or a directory of numbered images
+    #[arg(long, conflicts_with_all = ["image", "color", "generate", "ken_burns"])]
+    video: Option<PathBuf>,
+
+    /// Frames per second, overriding the video's own rate (default: 30 for
+    /// image sequences)
+    #[arg(long, requires = "video")]
+    video_fps: Option<f64>,
+}
+
+impl VideoArgs {
+    pub fn video(&self) -> Option<Video> {
+        self.video.clone().map(|path| Video {
+            path,
+            fps: self.video_fps,
+        })
+    }
+}
+
+#[derive(Args)]
+pub struct GeneratorArgs {
+    /// Animated background to generate instead of showing an image
+    #[arg(long, value_enum, conflicts_with_all = ["image", "color", "ken_burns"])]
+    generate: Option<GeneratorKind>,
+
+    /// Seed for the generated background's layout
+    #[arg(long, default_value_t = 1, requires = "generate")]
+    seed: u64,
+
+    /// Comma-separated colors to generate with (default: a built-in palette)
+    #[arg(long, value_delimiter = ',', requires = "generate")]
+    palette: Vec<Color>,
+
+    /// How fast the generated background moves
+    #[arg(long, default_value_t = 1.0, requires = "generate")]
+    generate_speed: f32,
+
+    /// Fraction of the output resolution to render at; the compositor
+    /// scales the result up
+    #[arg(long, default_value_t = 0.25, requires = "generate")]
+    render_scale: f32,
+
+    /// Frames drawn per second
+    #[arg(long, default_value_t = 30, requires = "generate")]
+    generate_fps: u32,
+}
+
+impl GeneratorArgs {
+    pub fn generator(&self) -> Option<Generator> {
+        self.generate.map(|kind| Generator {
+            kind,
+            seed: self.seed,
+            palette: self.palette.clone(),
+            speed: self.generate_speed,
+            scale: self.render_scale,
+            fps: self.generate_fps,
+        })
+    }
+}
+
+#[derive(Args)]
+pub struct TransitionArgs {
+    /// How the previous wallpaper gives way to the new one
+    #[arg(long, value_enum, default_value_t = TransitionKind::None)]
+    transition: TransitionKind,
+
+    /// Length of the transition, e.g. 800ms or 2s
+    #[arg(long, default_value = "800ms", value_parser = humantime::parse_duration)]
+    duration: Duration,
+
+    /// Direction of wipes and slides in degrees, clockwise from
+    /// left-to-right
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    angle: f32,
+
+    /// Point circles grow from or shrink to, as X,Y in the compositor's
+    /// logical coordinates, e.g. the cursor position (default: output center)
+    #[arg(long, value_parser = parse_offset)]
+    position: Option<(i32, i32)>,
+
+    /// Timing curve: linear, ease, ease-in, ease-out, ease-in-out, spring or
+    /// cubic-bezier(X1,Y1,X2,Y2)
+    #[arg(long, default_value = "linear")]
+    easing: Easing,
+
+    /// Most frames drawn per second (default: each output's refresh rate)
+    #[arg(long)]
+    fps: Option<u32>,
+}
+
+impl TransitionArgs {
+    pub fn transition(&self) -> Transition {
+        Transition {
+            kind: self.transition,
+            duration: self.duration,
+            angle: self.angle,
+            position: self.position,
+            easing: self.easing,
+            max_fps: self.fps,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Mode {
+    /// Scale the image to each output
+    Stretch,
+    /// Spread the image across all outputs
+    Span,
+    /// Repeat the image across each output
+    Tile,
+}
+
+impl ModeArgs {
+    /// Fails on options the chosen mode has no use for.
+    pub fn scaling_mode(self) -> WallpaperResult<ScalingMode> {
+        if !self.bezel.is_empty() && self.mode != Mode::Span {
+            return Err(WallpaperError::InvalidScaling(
+                "--bezel only applies to --mode span".to_string(),
+            ));
+        }
+        Ok(self.scaling_mode_as(self.mode))
+    }
+
+    /// The scaling mode of kind `mode`, with the other options as given.
+    pub fn scaling_mode_as(&self, mode: Mode) -> ScalingMode {
+        match mode {
+            Mode::Stretch => ScalingMode::Stretch,
+            Mode::Span => ScalingMode::Span {
+                bezels: self.bezel.clone(),
+            },
+            Mode::Tile => ScalingMode::Tile {
+                scale: self.tile_scale,
+                offset: self.tile_offset,
+                align: self.tile_align,
+            },
+        }
+    }
+}
+
+const MAX_TILE_SCALE: f64 = 100.0;
+
+fn parse_tile_scale(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(scale) if scale > 0.0 && scale <= MAX_TILE_SCALE => Ok(scale),
+        _ => Err(format!(
+            "invalid tile scale, expected more than 0 and at most {MAX_TILE_SCALE}: {s}"
+        )),
+    }
+}
+
+fn parse_offset(s: &str) -> Result<(i32, i32), String> {
+    s.split_once(',')
+        .and_then(|(x, y)| Some((x.trim().parse().ok()?, y.trim().parse().ok()?)))
+        .ok_or_else(|| format!("invalid offset, expected X,Y: {s}"))
+}
+
+fn parse_zoom(s: &str) -> Result<(f64, f64), String> {
+    s.split_once(',')
+        .and_then(|(min, max)| Some((min.trim().parse().ok()?, max.trim().parse().ok()?)))
+        .filter(|&(min, max): &(f64, f64)| min >= 1.0 && max >= min && max.is_finite())
+        .ok_or_else(|| format!("invalid zoom range, expected MIN,MAX from 1 up: {s}"))
+}
+
+/// Parses a fraction, either plain or as a percentage.
+fn parse_share(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let share = match s.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map(|p| p / 100.0),
+        None => s.parse::<f64>(),
+    };
+    share
+        .ok()
+        .filter(|share| share.is_finite() && *share >= 0.0)
+        .ok_or_else(|| format!("invalid share, expected e.g. 25% or 0.25: {s}"))
+}
+
+/// Parses a byte count with an optional K/M/G (powers of 1000) or
+/// KiB/MiB/GiB (powers of 1024) suffix.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let multiplier: usize = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000 * 1000,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => return Err(format!("invalid size unit: {s}")),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size: {s}"))
 }
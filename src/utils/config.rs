@@ -0,0 +1,45 @@
+use std::{path::PathBuf, time::Duration};
+
+/// Settings the daemon is started with.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Bytes of shared memory kept for rendered wallpapers.
+    pub cache_budget: usize,
+    /// Bytes kept for decoded source images.
+    pub image_cache_budget: usize,
+    /// Hash file contents into cache keys, catching edits that preserve
+    /// size and mtime.
+    pub hash_contents: bool,
+    /// Bytes of rendered frames kept on disk across restarts; 0 disables
+    /// the disk cache.
+    pub disk_cache_budget: u64,
+    /// How long an unused frame stays in the disk cache.
+    pub disk_cache_max_age: Duration,
+    /// Share of one CPU core animated wallpapers may use; they drop frames
+    /// to stay below it.
+    pub animation_cpu_budget: f64,
+    /// Animations pause once the user has been idle this long; zero never
+    /// pauses them.
+    pub idle_timeout: Duration,
+    /// Pause animations while no mains power is connected.
+    pub pause_on_battery: bool,
+    /// Where power supplies are listed, `/sys/class/power_supply` outside
+    /// of tests.
+    pub power_supply_path: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            cache_budget: 256 * 1024 * 1024,
+            image_cache_budget: 256 * 1024 * 1024,
+            hash_contents: false,
+            disk_cache_budget: 1024 * 1024 * 1024,
+            disk_cache_max_age: Duration::from_secs(30 * 24 * 60 * 60),
+            animation_cpu_budget: 0.25,
+            idle_timeout: Duration::from_secs(60),
+            pause_on_battery: false,
+            power_supply_path: PathBuf::from("/sys/class/power_supply"),
+        }
+    }
+}
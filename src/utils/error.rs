@@ -1,6 +1,6 @@
 use image::ImageError;
 use thiserror::Error;
-use wayland_client::{ConnectError, DispatchError};
+use wayland_client::{backend::WaylandError, ConnectError, DispatchError};
 
 #[derive(Error, Debug)]
 pub enum WallpaperError {
@@ -28,10 +28,34 @@ pub enum WallpaperError {
     #[error("Invalid scaling mode: {0}")]
     InvalidScaling(String),
 
+    #[error("Invalid color: {0}")]
+    InvalidColor(String),
+
+    #[error("Invalid easing: {0}")]
+    InvalidEasing(String),
+
+    #[error("Invalid video: {0}")]
+    InvalidVideo(String),
+
+    #[error("Invalid slideshow: {0}")]
+    InvalidSlideshow(String),
+
+    #[error("Invalid playlist: {0}")]
+    InvalidPlaylist(String),
+
+    #[error("Daemon error: {0}")]
+    Daemon(String),
+
     #[error("Wayland protocol error: {0}")]
     WaylandProtocol(String),
 }
 
+impl From<WaylandError> for WallpaperError {
+    fn from(err: WaylandError) -> Self {
+        Self::Wayland(DispatchError::Backend(err))
+    }
+}
+
 impl From<memfd::Error> for WallpaperError {
     fn from(err: memfd::Error) -> Self {
         Self::Memory(err.to_string())
@@ -0,0 +1,293 @@
+//! Playlist files: one entry per line as `PATH [KEY=VALUE]...`, where PATH
+//! may be double-quoted and start with `~/`, and relative paths are taken
+//! from the playlist's directory. Blank lines and lines starting with `#`
+//! are skipped. Keys:
+//!
+//! - `duration`: how long the entry stays up, e.g. `90s` or `15m`
+//! - `mode`: `stretch`, `span` or `tile`
+//! - `transition`: any transition kind, e.g. `fade`
+//! - `outputs`: comma-separated output names, e.g. `DP-1,HDMI-A-1`
+//!
+//! Options an entry leaves out come from the command line.
+
+use crate::{
+    core::{
+        slideshow::{self, Slide},
+        transition::{Transition, TransitionKind},
+    },
+    image::render::ScalingMode,
+};
+use clap::ValueEnum;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// Why one line of a playlist was rejected.
+#[derive(Debug)]
+pub struct LineError {
+    /// 1-based, as editors count.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// What entries get for the options they leave out.
+#[derive(Clone, Debug)]
+pub struct Defaults {
+    pub mode: ScalingMode,
+    /// What `mode=span` and `mode=tile` pick, set up like `mode`.
+    pub span: ScalingMode,
+    pub tile: ScalingMode,
+    pub transition: Transition,
+}
+
+/// Parses a playlist read from a file in `dir`, checking that every entry
+/// names a decodable image. Returns every bad line rather than the first.
+pub fn parse(text: &str, dir: &Path, defaults: &Defaults) -> Result<Vec<Slide>, Vec<LineError>> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    parse_with_home(text, dir, home.as_deref(), defaults)
+}
+
+/// Like `parse`, with `~/` expanding to `home`.
+fn parse_with_home(
+    text: &str,
+    dir: &Path,
+    home: Option<&Path>,
+    defaults: &Defaults,
+) -> Result<Vec<Slide>, Vec<LineError>> {
+    let mut slides = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_entry(line, dir, home, defaults) {
+            Ok(slide) => slides.push(slide),
+            Err(message) => errors.push(LineError {
+                line: i + 1,
+                message,
+            }),
+        }
+    }
+    if errors.is_empty() {
+        Ok(slides)
+    } else {
+        Err(errors)
+    }
+}
+
+fn parse_entry(
+    line: &str,
+    dir: &Path,
+    home: Option<&Path>,
+    defaults: &Defaults,
+) -> Result<Slide, String> {
+    let (path, options) = match line.strip_prefix('"') {
+        Some(quoted) => quoted
+            .split_once('"')
+            .ok_or_else(|| "unterminated quote".to_string())?,
+        None => line.split_once(char::is_whitespace).unwrap_or((line, "")),
+    };
+
+    let path = resolve(path, dir, home);
+    if !path.is_file() {
+        return Err(format!("no such file: {}", path.display()));
+    }
+    if !slideshow::is_image(&path) {
+        return Err(format!("not a supported image: {}", path.display()));
+    }
+
+    let mut slide = Slide {
+        path,
+        duration: None,
+        mode: defaults.mode.clone(),
+        transition: defaults.transition,
+        outputs: Vec::new(),
+    };
+    for option in options.split_whitespace() {
+        let (key, value) = option
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE, got '{option}'"))?;
+        match key {
+            "duration" => match humantime::parse_duration(value) {
+                Ok(duration) if !duration.is_zero() => slide.duration = Some(duration),
+                Ok(_) => return Err("duration must be positive".to_string()),
+                Err(e) => return Err(format!("invalid duration '{value}': {e}")),
+            },
+            "mode" => {
+                slide.mode = match value.to_ascii_lowercase().as_str() {
+                    "stretch" => ScalingMode::Stretch,
+                    "span" => defaults.span.clone(),
+                    "tile" => defaults.tile.clone(),
+                    _ => return Err(format!("invalid mode '{value}'")),
+                };
+            }
+            "transition" => {
+                slide.transition.kind = TransitionKind::from_str(value, true)
+                    .map_err(|_| format!("invalid transition '{value}'"))?;
+            }
+            "outputs" => {
+                slide.outputs = value
+                    .split(',')
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+            _ => return Err(format!("unknown key '{key}'")),
+        }
+    }
+    Ok(slide)
+}
+
+/// Expands `~/` to `home` and makes `path` absolute relative to `dir`, since
+/// the daemon doesn't share the client's working directory.
+fn resolve(path: &str, dir: &Path, home: Option<&Path>) -> PathBuf {
+    let path = match (path.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    };
+    let path = dir.join(path);
+    path.canonicalize().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, time::Duration};
+    use tempfile::TempDir;
+
+    /// A directory holding two images and a text file.
+    fn temp_dir() -> TempDir {
+        let tmp = TempDir::new().unwrap();
+        for image in ["a.png", "with space.png"] {
+            image::RgbaImage::new(1, 1)
+                .save(tmp.path().join(image))
+                .unwrap();
+        }
+        fs::write(tmp.path().join("notes.txt"), "not an image").unwrap();
+        tmp
+    }
+
+    fn defaults() -> Defaults {
+        Defaults {
+            mode: ScalingMode::Stretch,
+            span: ScalingMode::Span { bezels: Vec::new() },
+            tile: ScalingMode::Tile {
+                scale: 2.0,
+                offset: (1, 2),
+                align: true,
+            },
+            transition: Transition::default(),
+        }
+    }
+
+    #[test]
+    fn parses_entries() {
+        let tmp = temp_dir();
+        let dir = tmp.path();
+        let defaults = defaults();
+        let cases: &[(&str, Slide)] = &[
+            (
+                "a.png",
+                Slide {
+                    path: dir.join("a.png"),
+                    duration: None,
+                    mode: ScalingMode::Stretch,
+                    transition: Transition::default(),
+                    outputs: Vec::new(),
+                },
+            ),
+            (
+                "\"with space.png\" duration=90s",
+                Slide {
+                    path: dir.join("with space.png"),
+                    duration: Some(Duration::from_secs(90)),
+                    mode: ScalingMode::Stretch,
+                    transition: Transition::default(),
+                    outputs: Vec::new(),
+                },
+            ),
+            (
+                "  a.png   mode=Tile transition=fade outputs=DP-1,,HDMI-A-1  ",
+                Slide {
+                    path: dir.join("a.png"),
+                    duration: None,
+                    mode: defaults.tile.clone(),
+                    transition: Transition {
+                        kind: TransitionKind::Fade,
+                        ..Transition::default()
+                    },
+                    outputs: vec!["DP-1".to_string(), "HDMI-A-1".to_string()],
+                },
+            ),
+        ];
+        for (line, expected) in cases {
+            let slides = parse(line, dir, &defaults).unwrap();
+            assert_eq!(slides.len(), 1, "{line}");
+            assert_eq!(&slides[0], expected, "{line}");
+        }
+
+        let absolute = format!("{}", dir.join("a.png").display());
+        let slides = parse(&absolute, Path::new("/elsewhere"), &defaults).unwrap();
+        assert_eq!(slides[0].path, dir.join("a.png"));
+    }
+
+    #[test]
+    fn expands_home() {
+        let tmp = temp_dir();
+        let dir = tmp.path();
+        let text = "~/a.png\n\"~/with space.png\"";
+        let slides = parse_with_home(text, Path::new("/"), Some(dir), &defaults()).unwrap();
+        let paths: Vec<_> = slides.into_iter().map(|slide| slide.path).collect();
+        assert_eq!(paths, [dir.join("a.png"), dir.join("with space.png")]);
+
+        // Without a home, `~` is just a directory name.
+        let errors = parse_with_home("~/a.png", dir, None, &defaults()).unwrap_err();
+        assert!(errors[0].message.contains("no such file"));
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        let tmp = temp_dir();
+        let dir = tmp.path();
+        let cases = [
+            ("missing.png", "no such file"),
+            ("notes.txt", "not a supported image"),
+            ("\"a.png duration=5s", "unterminated quote"),
+            ("a.png color=red", "unknown key 'color'"),
+            ("a.png duration", "expected KEY=VALUE"),
+            ("a.png duration=0s", "duration must be positive"),
+            ("a.png duration=0ms", "duration must be positive"),
+            ("a.png duration=0", "invalid duration"),
+            ("a.png duration=soon", "invalid duration"),
+            ("a.png mode=fit", "invalid mode"),
+            ("a.png transition=spin", "invalid transition"),
+        ];
+        for (line, message) in cases {
+            let errors = parse(line, dir, &defaults()).unwrap_err();
+            assert_eq!(errors.len(), 1, "{line}");
+            assert!(errors[0].message.contains(message), "{line}: {}", errors[0]);
+        }
+    }
+
+    #[test]
+    fn reports_every_bad_line_by_number() {
+        let tmp = temp_dir();
+        let dir = tmp.path();
+        let text = "# comment\n\na.png\nmissing.png\n  # indented comment\na.png bogus=1\n\"with space.png\"\na.png duration=0s\n";
+        let errors = parse(text, dir, &defaults()).unwrap_err();
+        let lines: Vec<_> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, [4, 6, 8]);
+        assert!(errors[1].to_string().starts_with("line 6: unknown key"));
+        assert!(parse("# only comments\n\n", dir, &defaults())
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+use log::{debug, info};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How often the power supply state is re-read.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Tracks whether the machine runs on battery, by polling a
+/// `/sys/class/power_supply`-style tree.
+pub struct PowerMonitor {
+    path: PathBuf,
+    on_battery: bool,
+    next_check: Instant,
+}
+
+impl PowerMonitor {
+    pub fn new(path: PathBuf) -> Self {
+        let on_battery = read_on_battery(&path);
+        debug!(
+            "Watching power supplies in {} (on battery: {})",
+            path.display(),
+            on_battery
+        );
+        Self {
+            path,
+            on_battery,
+            next_check: Instant::now() + CHECK_INTERVAL,
+        }
+    }
+
+    /// Whether the machine is on battery, re-reading the tree if the last
+    /// read is older than the check interval.
+    pub fn on_battery(&mut self, now: Instant) -> bool {
+        if now >= self.next_check {
+            let on_battery = read_on_battery(&self.path);
+            if on_battery != self.on_battery {
+                info!(
+                    "Power source changed to {}",
+                    if on_battery { "battery" } else { "AC" }
+                );
+            }
+            self.on_battery = on_battery;
+            self.next_check = now + CHECK_INTERVAL;
+        }
+        self.on_battery
+    }
+
+    pub fn next_check(&self) -> Instant {
+        self.next_check
+    }
+}
+
+/// On battery means no mains or USB supply is online while some battery
+/// discharges. Unreadable trees count as mains power.
+fn read_on_battery(path: &Path) -> bool {
+    let Ok(supplies) = fs::read_dir(path) else {
+        return false;
+    };
+    let read = |dir: &Path, file: &str| {
+        fs::read_to_string(dir.join(file))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    };
+
+    let mut discharging = false;
+    for supply in supplies.filter_map(Result::ok) {
+        let dir = supply.path();
+        match read(&dir, "type").as_str() {
+            "Mains" | "USB" if read(&dir, "online") == "1" => return false,
+            "Battery" if read(&dir, "status") == "Discharging" => discharging = true,
+            _ => {}
+        }
+    }
+    discharging
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A fresh `power_supply`-style tree holding `supplies`, each given as
+    /// its name and the files in it.
+    fn tree(supplies: &[(&str, &[(&str, &str)])]) -> TempDir {
+        let root = TempDir::new().unwrap();
+        for (supply, files) in supplies {
+            let dir = root.path().join(supply);
+            fs::create_dir(&dir).unwrap();
+            for (file, contents) in *files {
+                fs::write(dir.join(file), format!("{contents}\n")).unwrap();
+            }
+        }
+        root
+    }
+
+    const DISCHARGING: &[(&str, &str)] = &[("type", "Battery"), ("status", "Discharging")];
+
+    #[test]
+    fn mains_online_is_not_battery() {
+        let root = tree(&[
+            ("AC", &[("type", "Mains"), ("online", "1")]),
+            ("BAT0", &[("type", "Battery"), ("status", "Charging")]),
+        ]);
+        assert!(!read_on_battery(root.path()));
+    }
+
+    #[test]
+    fn discharging_without_mains_is_battery() {
+        let root = tree(&[
+            ("AC", &[("type", "Mains"), ("online", "0")]),
+            ("BAT0", DISCHARGING),
+        ]);
+        assert!(read_on_battery(root.path()));
+    }
+
+    #[test]
+    fn usb_online_overrides_a_discharging_battery() {
+        let root = tree(&[
+            ("BAT0", DISCHARGING),
+            ("ucsi-source-psy-1", &[("type", "USB"), ("online", "1")]),
+        ]);
+        assert!(!read_on_battery(root.path()));
+    }
+
+    #[test]
+    fn full_battery_and_empty_tree_are_not_battery() {
+        let root = tree(&[("BAT0", &[("type", "Battery"), ("status", "Full")])]);
+        assert!(!read_on_battery(root.path()));
+
+        let root = tree(&[]);
+        assert!(!read_on_battery(root.path()));
+    }
+
+    #[test]
+    fn missing_directory_is_not_battery() {
+        let root = TempDir::new().unwrap();
+        assert!(!read_on_battery(&root.path().join("missing")));
+    }
+}
@@ -1,21 +1,35 @@
 use wayland_client::{
     protocol::{
-        wl_buffer, wl_callback, wl_compositor, wl_output, wl_region, wl_registry, wl_shm,
+        wl_buffer, wl_callback, wl_compositor, wl_output, wl_region, wl_registry, wl_seat, wl_shm,
         wl_shm_pool, wl_surface,
     },
-    Connection, Dispatch, Proxy, QueueHandle,
+    Connection, Dispatch, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols::{
+    ext::idle_notify::v1::client::{ext_idle_notification_v1, ext_idle_notifier_v1},
+    wp::{
+        presentation_time::client::{wp_presentation, wp_presentation_feedback},
+        single_pixel_buffer::v1::client::wp_single_pixel_buffer_manager_v1,
+        viewporter::client::{wp_viewport, wp_viewporter},
+    },
+    xdg::xdg_output::zv1::client::{zxdg_output_manager_v1, zxdg_output_v1},
 };
-use wayland_protocols::wp::viewporter::client::{wp_viewport, wp_viewporter};
 use wayland_protocols_wlr::layer_shell::v1::client::{
     zwlr_layer_shell_v1::ZwlrLayerShellV1,
     zwlr_layer_surface_v1::{self, ZwlrLayerSurfaceV1},
 };
 
 use crate::{
-    core::backend::LayerSurface, display::monitor::Monitor, utils::error::WallpaperResult,
+    core::{
+        backend::LayerSurface,
+        buffer::BufferState,
+        presentation::{self, FeedbackData},
+    },
+    display::monitor::Monitor,
+    utils::error::WallpaperResult,
 };
 use log::{debug, info};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 macro_rules! impl_empty_dispatch {
     ($($t:ty),*) => {
@@ -38,9 +52,21 @@ pub struct WaylandState {
     pub(crate) monitors: Vec<Monitor>,
     pub(crate) outputs: HashMap<u32, wl_output::WlOutput>,
     pub(crate) shm: Option<wl_shm::WlShm>,
+    pub(crate) shm_formats: Vec<wl_shm::Format>,
     pub(crate) layer_shell: Option<ZwlrLayerShellV1>,
     pub(crate) compositor: Option<wl_compositor::WlCompositor>,
     pub(crate) viewporter: Option<wp_viewporter::WpViewporter>,
+    pub(crate) xdg_output_manager: Option<zxdg_output_manager_v1::ZxdgOutputManagerV1>,
+    pub(crate) single_pixel_buffer_manager:
+        Option<wp_single_pixel_buffer_manager_v1::WpSinglePixelBufferManagerV1>,
+    pub(crate) presentation: Option<wp_presentation::WpPresentation>,
+    /// Clock presentation timestamps are given on.
+    pub(crate) presentation_clock: Option<u32>,
+    pub(crate) seat: Option<wl_seat::WlSeat>,
+    pub(crate) idle_notifier: Option<ext_idle_notifier_v1::ExtIdleNotifierV1>,
+    pub(crate) idle_notification: Option<ext_idle_notification_v1::ExtIdleNotificationV1>,
+    /// Whether the compositor reports the user as idle.
+    pub(crate) idle: bool,
     pub(crate) layer_surfaces: HashMap<u32, LayerSurface>,
 }
 
@@ -50,9 +76,18 @@ impl WaylandState {
             monitors: Vec::new(),
             outputs: HashMap::new(),
             shm: None,
+            shm_formats: Vec::new(),
             layer_shell: None,
             compositor: None,
             viewporter: None,
+            xdg_output_manager: None,
+            single_pixel_buffer_manager: None,
+            presentation: None,
+            presentation_clock: None,
+            seat: None,
+            idle_notifier: None,
+            idle_notification: None,
+            idle: false,
             layer_surfaces: HashMap::new(),
         };
 
@@ -69,6 +104,16 @@ impl WaylandState {
         self.shm.as_ref().expect("SHM should be initialized")
     }
 
+    /// The cheapest opaque format to upload decoded images in. XBGR8888
+    /// matches RGBA byte order; XRGB8888 is always supported.
+    pub fn preferred_format(&self) -> wl_shm::Format {
+        if self.shm_formats.contains(&wl_shm::Format::Xbgr8888) {
+            wl_shm::Format::Xbgr8888
+        } else {
+            wl_shm::Format::Xrgb8888
+        }
+    }
+
     pub fn get_layer_shell(&self) -> &ZwlrLayerShellV1 {
         self.layer_shell
             .as_ref()
@@ -87,6 +132,47 @@ impl WaylandState {
             .expect("Viewporter should be initialized")
     }
 
+    pub fn get_presentation(&self) -> Option<&wp_presentation::WpPresentation> {
+        self.presentation.as_ref()
+    }
+
+    pub fn get_single_pixel_buffer_manager(
+        &self,
+    ) -> Option<&wp_single_pixel_buffer_manager_v1::WpSinglePixelBufferManagerV1> {
+        self.single_pixel_buffer_manager.as_ref()
+    }
+
+    fn monitor_for_output(&mut self, output: &wl_output::WlOutput) -> Option<&mut Monitor> {
+        self.monitors
+            .iter_mut()
+            .find(|m| m.output.id() == output.id())
+    }
+
+    fn bind_xdg_output(&mut self, index: usize, qh: &QueueHandle<Self>) {
+        let Some(manager) = &self.xdg_output_manager else {
+            return;
+        };
+        let monitor = &mut self.monitors[index];
+        if monitor.xdg_output.is_none() {
+            monitor.xdg_output = Some(manager.get_xdg_output(&monitor.output, qh, monitor.name));
+        }
+    }
+
+    /// Asks to be told when the user has been idle for `timeout`. Does
+    /// nothing without a seat or `ext_idle_notifier_v1`.
+    pub fn watch_idle(&mut self, timeout: Duration, qh: &QueueHandle<Self>) {
+        let (Some(notifier), Some(seat)) = (&self.idle_notifier, &self.seat) else {
+            info!("Idle notifications unavailable, animations run while idle");
+            return;
+        };
+        let timeout = timeout.as_millis().min(u32::MAX as u128) as u32;
+        self.idle_notification = Some(notifier.get_idle_notification(timeout, seat, qh, ()));
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
     pub fn add_layer_surface(&mut self, id: u32, surface: LayerSurface) {
         debug!("Adding layer surface with id: {}", id);
         self.layer_surfaces.insert(id, surface);
@@ -122,6 +208,7 @@ impl Dispatch<wl_registry::WlRegistry, ()> for WaylandState {
                         let output =
                             registry.bind::<wl_output::WlOutput, _, _>(name, version, qh, ());
                         state.monitors.push(Monitor::new(output, name));
+                        state.bind_xdg_output(state.monitors.len() - 1, qh);
                         info!("Registered output device");
                     }
                     "wl_compositor" => {
@@ -155,6 +242,57 @@ impl Dispatch<wl_registry::WlRegistry, ()> for WaylandState {
                         state.viewporter = Some(viewporter);
                         info!("Registered viewporter");
                     }
+                    "zxdg_output_manager_v1" => {
+                        let manager = registry
+                            .bind::<zxdg_output_manager_v1::ZxdgOutputManagerV1, _, _>(
+                                name,
+                                version.min(3),
+                                qh,
+                                (),
+                            );
+                        state.xdg_output_manager = Some(manager);
+                        for index in 0..state.monitors.len() {
+                            state.bind_xdg_output(index, qh);
+                        }
+                        info!("Registered xdg output manager");
+                    }
+                    "wp_single_pixel_buffer_manager_v1" => {
+                        let manager = registry.bind::<
+                            wp_single_pixel_buffer_manager_v1::WpSinglePixelBufferManagerV1,
+                            _,
+                            _,
+                        >(name, version, qh, ());
+                        state.single_pixel_buffer_manager = Some(manager);
+                        info!("Registered single pixel buffer manager");
+                    }
+                    "wp_presentation" => {
+                        let presentation = registry.bind::<wp_presentation::WpPresentation, _, _>(
+                            name,
+                            version,
+                            qh,
+                            (),
+                        );
+                        state.presentation = Some(presentation);
+                        info!("Registered presentation time");
+                    }
+                    // Any seat will do; idle time is tracked across seats.
+                    "wl_seat" if state.seat.is_none() => {
+                        let seat =
+                            registry.bind::<wl_seat::WlSeat, _, _>(name, version.min(7), qh, ());
+                        state.seat = Some(seat);
+                        info!("Registered seat");
+                    }
+                    "ext_idle_notifier_v1" => {
+                        let notifier = registry
+                            .bind::<ext_idle_notifier_v1::ExtIdleNotifierV1, _, _>(
+                                name,
+                                version.min(1),
+                                qh,
+                                (),
+                            );
+                        state.idle_notifier = Some(notifier);
+                        info!("Registered idle notifier");
+                    }
                     _ => {}
                 }
             }
@@ -179,25 +317,26 @@ impl Dispatch<wl_output::WlOutput, ()> for WaylandState {
             wl_output::Event::Geometry {
                 x,
                 y,
-                physical_width: _,
-                physical_height: _,
+                physical_width,
+                physical_height,
                 subpixel: _,
                 make,
                 model,
-                transform: _,
+                transform,
             } => {
                 debug!(
-                    "Output geometry: pos=({}, {}), make={}, model={}",
-                    x, y, make, model
+                    "Output geometry: pos=({}, {}), size={}x{}mm, make={}, model={}",
+                    x, y, physical_width, physical_height, make, model
                 );
 
-                if let Some(monitor) = state
-                    .monitors
-                    .iter_mut()
-                    .find(|m| m.output.id() == output.id())
-                {
+                if let Some(monitor) = state.monitor_for_output(output) {
                     monitor.x = x;
                     monitor.y = y;
+                    monitor.width_mm = physical_width;
+                    monitor.height_mm = physical_height;
+                    if let WEnum::Value(transform) = transform {
+                        monitor.transform = transform;
+                    }
                 }
             }
             wl_output::Event::Mode {
@@ -207,16 +346,29 @@ impl Dispatch<wl_output::WlOutput, ()> for WaylandState {
                 refresh,
             } => {
                 debug!("Output mode: {}x{}", width, height);
-                if let Some(monitor) = state
-                    .monitors
-                    .iter_mut()
-                    .find(|m| m.output.id() == output.id())
-                {
+                if let Some(monitor) = state.monitor_for_output(output) {
                     monitor.width = width;
                     monitor.height = height;
                     monitor.refresh = refresh;
                 }
             }
+            wl_output::Event::Scale { factor } => {
+                debug!("Output scale: {}", factor);
+                if let Some(monitor) = state.monitor_for_output(output) {
+                    monitor.scale = factor as f64;
+                }
+            }
+            // wl_output v4 names supersede the deprecated xdg-output ones.
+            wl_output::Event::Name { name } => {
+                if let Some(monitor) = state.monitor_for_output(output) {
+                    monitor.output_name = Some(name);
+                }
+            }
+            wl_output::Event::Description { description } => {
+                if let Some(monitor) = state.monitor_for_output(output) {
+                    monitor.description = Some(description);
+                }
+            }
             wl_output::Event::Done => {
                 debug!("Output configuration done");
             }
@@ -225,24 +377,74 @@ impl Dispatch<wl_output::WlOutput, ()> for WaylandState {
     }
 }
 
-impl Dispatch<wl_buffer::WlBuffer, ()> for WaylandState {
+impl Dispatch<zxdg_output_v1::ZxdgOutputV1, u32> for WaylandState {
+    fn event(
+        state: &mut Self,
+        _: &zxdg_output_v1::ZxdgOutputV1,
+        event: zxdg_output_v1::Event,
+        name: &u32,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let Some(monitor) = state.monitors.iter_mut().find(|m| m.name == *name) else {
+            return;
+        };
+
+        match event {
+            zxdg_output_v1::Event::LogicalPosition { x, y } => {
+                debug!("Output logical position: ({}, {})", x, y);
+                monitor.logical_x = x;
+                monitor.logical_y = y;
+            }
+            zxdg_output_v1::Event::LogicalSize { width, height } => {
+                debug!("Output logical size: {}x{}", width, height);
+                monitor.logical_width = width;
+                monitor.logical_height = height;
+            }
+            // Only used when wl_output is too old to name the output.
+            zxdg_output_v1::Event::Name { name } => {
+                debug!("Output name: {}", name);
+                monitor.output_name.get_or_insert(name);
+            }
+            zxdg_output_v1::Event::Description { description } => {
+                monitor.description.get_or_insert(description);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<wl_shm::WlShm, ()> for WaylandState {
     fn event(
         state: &mut Self,
+        _: &wl_shm::WlShm,
+        event: wl_shm::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_shm::Event::Format {
+            format: WEnum::Value(format),
+        } = event
+        {
+            debug!("SHM format supported: {:?}", format);
+            state.shm_formats.push(format);
+        }
+    }
+}
+
+impl Dispatch<wl_buffer::WlBuffer, Arc<BufferState>> for WaylandState {
+    fn event(
+        _: &mut Self,
         buffer: &wl_buffer::WlBuffer,
         event: wl_buffer::Event,
-        _: &(),
+        buffer_state: &Arc<BufferState>,
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
         if let wl_buffer::Event::Release = event {
             debug!("Buffer released: {:?}", buffer.id());
-            for surface in state.layer_surfaces.values_mut() {
-                if let Some(pending) = surface.take_pending_buffer() {
-                    if pending.buffer().id() == buffer.id() {
-                        pending.set_released(true);
-                    }
-                }
-            }
+            buffer_state.set_released(true);
         }
     }
 }
@@ -297,24 +499,110 @@ impl Dispatch<wl_callback::WlCallback, ()> for WaylandState {
         event: wl_callback::Event,
         _: &(),
         _: &Connection,
-        qh: &QueueHandle<Self>,
+        _: &QueueHandle<Self>,
     ) {
         if let wl_callback::Event::Done { callback_data: _ } = event {
             debug!("Frame callback completed: {:?}", callback.id());
-            if let Some(surface) = state.layer_surfaces.values_mut().find(|s| s.needs_redraw()) {
-                surface.handle_frame(callback, qh);
+            if let Some(surface) = state
+                .layer_surfaces
+                .values_mut()
+                .find(|s| s.owns_frame_callback(callback))
+            {
+                surface.handle_frame(callback);
+            }
+        }
+    }
+}
+
+impl Dispatch<wp_presentation::WpPresentation, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        _: &wp_presentation::WpPresentation,
+        event: wp_presentation::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wp_presentation::Event::ClockId { clk_id } = event {
+            debug!("Presentation clock: {}", clk_id);
+            state.presentation_clock = Some(clk_id);
+        }
+    }
+}
+
+impl Dispatch<wp_presentation_feedback::WpPresentationFeedback, FeedbackData> for WaylandState {
+    fn event(
+        state: &mut Self,
+        _: &wp_presentation_feedback::WpPresentationFeedback,
+        event: wp_presentation_feedback::Event,
+        data: &FeedbackData,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let clock = state.presentation_clock;
+        let Some(surface) = state.layer_surfaces.get_mut(&data.surface) else {
+            return;
+        };
+        match event {
+            wp_presentation_feedback::Event::Presented {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+                refresh,
+                ..
+            } => {
+                let secs = (tv_sec_hi as u64) << 32 | tv_sec_lo as u64;
+                let Some(time) =
+                    clock.and_then(|clock| presentation::to_instant(clock, secs, tv_nsec))
+                else {
+                    return;
+                };
+                // A refresh of 0 means the output has no fixed rate.
+                let refresh = (refresh > 0).then(|| Duration::from_nanos(refresh as u64));
+                surface.handle_presented(time, refresh, data.target);
+            }
+            wp_presentation_feedback::Event::Discarded => {
+                debug!("Frame discarded on surface {}", data.surface);
+                surface.handle_discarded();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        _: &ext_idle_notification_v1::ExtIdleNotificationV1,
+        event: ext_idle_notification_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_idle_notification_v1::Event::Idled => {
+                debug!("User went idle");
+                state.idle = true;
+            }
+            ext_idle_notification_v1::Event::Resumed => {
+                debug!("User is back");
+                state.idle = false;
             }
+            _ => {}
         }
     }
 }
 
 impl_empty_dispatch!(
-    wl_shm::WlShm,
     ZwlrLayerShellV1,
     wl_surface::WlSurface,
     wl_shm_pool::WlShmPool,
     wl_compositor::WlCompositor,
     wp_viewport::WpViewport,
     wp_viewporter::WpViewporter,
-    wl_region::WlRegion
+    wl_region::WlRegion,
+    zxdg_output_manager_v1::ZxdgOutputManagerV1,
+    wp_single_pixel_buffer_manager_v1::WpSinglePixelBufferManagerV1,
+    wl_seat::WlSeat,
+    ext_idle_notifier_v1::ExtIdleNotifierV1
 );